@@ -86,5 +86,6 @@ async fn main() {
     let stdout = tokio::io::stdout();
 
     let (service, messages) = LspService::new(|client| Backend { client });
-    Server::new(stdin, stdout).interleave(messages).serve(service).await;
+    let status = Server::new(stdin, stdout).interleave(messages).serve(service).await;
+    std::process::exit(status.map_or(1, |status| status.code()));
 }
@@ -110,7 +110,7 @@ async fn main() -> anyhow::Result<()> {
     let (read, write) = tokio::io::split(stream);
 
     let (service, messages) = LspService::new(|client| Backend { client });
-    Server::new(read, write).interleave(messages).serve(service).await;
+    Server::new(read, write).interleave(messages).serve(service).await?;
 
     Ok(())
 }
@@ -113,7 +113,7 @@ async fn main() -> anyhow::Result<()> {
             let stream = WsStream::new(accept_async(socket).await?);
             let (read, write) = tokio::io::split(stream);
             let (service, messages) = LspService::new(|client| Backend { client });
-            Server::new(read, write).interleave(messages).serve(service).await;
+            Server::new(read, write).interleave(messages).serve(service).await?;
             Ok::<_, anyhow::Error>(())
         });
     }
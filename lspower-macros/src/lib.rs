@@ -51,12 +51,12 @@ struct MethodCall<'a> {
     result: Option<&'a syn::Type>,
 }
 
-fn parse_method_calls(lang_server_trait: &ItemTrait) -> Vec<MethodCall> {
+fn parse_method_calls(lang_server_trait: &ItemTrait) -> Vec<MethodCall<'_>> {
     let mut calls = Vec::new();
 
     for item in &lang_server_trait.items {
         let method = match item {
-            TraitItem::Method(m) if m.sig.ident == "request_else" => continue,
+            TraitItem::Method(m) if m.sig.ident == "request_else" || m.sig.ident == "notification_else" => continue,
             TraitItem::Method(m) => m,
             _ => continue,
         };
@@ -66,12 +66,11 @@ fn parse_method_calls(lang_server_trait: &ItemTrait) -> Vec<MethodCall> {
             .iter()
             .filter_map(|attr| attr.parse_args::<Meta>().ok())
             .filter(|meta| meta.path().is_ident("name"))
-            .find_map(|meta| match meta {
-                Meta::NameValue(MetaNameValue { lit: Lit::Str(lit), .. }) => {
-                    Some(lit.value().trim_matches('"').to_owned())
-                },
+            .map(|meta| match meta {
+                Meta::NameValue(MetaNameValue { lit: Lit::Str(lit), .. }) => lit.value().trim_matches('"').to_owned(),
                 _ => panic!("expected string literal for `#[rpc(name = ???)]` attribute"),
             })
+            .next()
             .expect("expected `#[rpc(name = \"foo\")]` attribute");
 
         let params = method.sig.inputs.iter().nth(1).and_then(|arg| match arg {
@@ -107,6 +106,9 @@ fn gen_server_router(trait_name: &syn::Ident, methods: &[MethodCall]) -> proc_ma
         .map(|(method, var_name)| {
             let rpc_name = &method.rpc_name;
             let variant = match (method.result.is_some(), method.params) {
+                (true, Some(_)) if rpc_name == "initialize" => {
+                    quote!(#var_name { params: Params<crate::server::InitializeParams>, id: Id },)
+                },
                 (true, Some(p)) => quote!(#var_name { params: Params<#p>, id: Id },),
                 (true, None) => quote!(#var_name { id: Id },),
                 (false, Some(p)) => quote!(#var_name { params: Params<#p> },),
@@ -140,17 +142,28 @@ fn gen_server_router(trait_name: &syn::Ident, methods: &[MethodCall]) -> proc_ma
                 (true, true) if rpc_name == "initialize" => quote! {
                     (ServerMethod::#var_name { params: Valid(p), id }, StateKind::Uninitialized) => {
                         state.set(StateKind::Initializing);
+                        session.initialize(&p);
                         let state = state.clone();
+                        let session = session.clone();
+                        let pending = pending.clone();
                         Box::pin(async move {
-                            let res = match server.#handler(p).await {
+                            let res = match server.#handler(p.params).await {
                                 Ok(result) => {
-                                    let result = serde_json::to_value(result).unwrap();
+                                    let mut result = serde_json::to_value(result).unwrap();
+                                    let encoding = session.position_encoding();
+                                    if encoding != crate::PositionEncoding::Utf16 {
+                                        result["capabilities"]["positionEncoding"] = encoding.as_str().into();
+                                    }
                                     info!("language server initialized");
                                     state.set(StateKind::Initialized);
+                                    if let Some((pid, interval)) = session.parent_process_to_watch() {
+                                        crate::server::watch_parent_process(pid, interval, state, pending);
+                                    }
                                     Response::ok(id, result)
                                 }
                                 Err(error) => {
                                     state.set(StateKind::Uninitialized);
+                                    session.reset();
                                     Response::error(Some(id), error)
                                 },
                             };
@@ -174,15 +187,16 @@ fn gen_server_router(trait_name: &syn::Ident, methods: &[MethodCall]) -> proc_ma
                         info!("shutdown request received, shutting down");
                         state.set(StateKind::ShutDown);
                         pending
-                            .execute(id, async move { server.#handler().await })
+                            .execute(id, #rpc_name, async move { server.#handler().await })
                             .map(|v| Ok(Some(Outgoing::Response(v))))
                             .boxed()
                     }
                 },
                 (true, true) => quote! {
                     (ServerMethod::#var_name { params: Valid(p), id }, StateKind::Initialized) => {
+                        let document = pending.document_of(#rpc_name, &p);
                         pending
-                            .execute(id, async move { server.#handler(p).await })
+                            .execute_on(id, #rpc_name, document, async move { server.#handler(p).await })
                             .map(|v| Ok(Some(Outgoing::Response(v))))
                             .boxed()
                     }
@@ -195,11 +209,48 @@ fn gen_server_router(trait_name: &syn::Ident, methods: &[MethodCall]) -> proc_ma
                 (true, false) => quote! {
                     (ServerMethod::#var_name { id }, StateKind::Initialized) => {
                         pending
-                            .execute(id, async move { server.#handler().await })
+                            .execute(id, #rpc_name, async move { server.#handler().await })
                             .map(|v| Ok(Some(Outgoing::Response(v))))
                             .boxed()
                     }
                 },
+                (false, true) if rpc_name == "textDocument/didOpen" => quote! {
+                    (ServerMethod::#var_name { params: Valid(p) }, StateKind::Initialized) => {
+                        if let Some(documents) = documents {
+                            documents.open(&p);
+                        }
+                        Box::pin(async move { server.#handler(p).await; Ok(None) })
+                    }
+                    (ServerMethod::#var_name { .. }, StateKind::Initialized) => {
+                        warn!("invalid parameters for {:?} notification", #rpc_name);
+                        future::ok(None).boxed()
+                    }
+                },
+                (false, true) if rpc_name == "textDocument/didChange" => quote! {
+                    (ServerMethod::#var_name { params: Valid(p) }, StateKind::Initialized) => {
+                        pending.invalidate(&p.text_document.uri);
+                        if let Some(documents) = documents {
+                            documents.change(&p, session.position_encoding());
+                        }
+                        Box::pin(async move { server.#handler(p).await; Ok(None) })
+                    }
+                    (ServerMethod::#var_name { .. }, StateKind::Initialized) => {
+                        warn!("invalid parameters for {:?} notification", #rpc_name);
+                        future::ok(None).boxed()
+                    }
+                },
+                (false, true) if rpc_name == "textDocument/didClose" => quote! {
+                    (ServerMethod::#var_name { params: Valid(p) }, StateKind::Initialized) => {
+                        if let Some(documents) = documents {
+                            documents.close(&p);
+                        }
+                        Box::pin(async move { server.#handler(p).await; Ok(None) })
+                    }
+                    (ServerMethod::#var_name { .. }, StateKind::Initialized) => {
+                        warn!("invalid parameters for {:?} notification", #rpc_name);
+                        future::ok(None).boxed()
+                    }
+                },
                 (false, true) => quote! {
                     (ServerMethod::#var_name { params: Valid(p) }, StateKind::Initialized) => {
                         Box::pin(async move { server.#handler(p).await; Ok(None) })
@@ -218,13 +269,20 @@ fn gen_server_router(trait_name: &syn::Ident, methods: &[MethodCall]) -> proc_ma
         })
         .collect();
 
+    let rpc_names = methods.iter().map(|method| method.rpc_name.as_str());
+    let request_names = methods
+        .iter()
+        .filter(|method| method.result.is_some())
+        .map(|method| method.rpc_name.as_str());
+
     quote! {
         mod generated_impl {
             use super::{#trait_name};
             use crate::{
-                jsonrpc::{not_initialized_error, Error, ErrorCode, Id, Outgoing, Response, ServerRequests, Version},
-                server::{State, StateKind},
-                service::ExitedError,
+                documents::DocumentStore,
+                jsonrpc::{not_initialized_error, Error, ErrorCode, Id, Outgoing, Params, Response, ServerRequests, Version},
+                server::{Session, State, StateKind},
+                service::{CustomMethods, ExitedError},
             };
             use futures::{future, FutureExt};
             use log::{error, info, warn};
@@ -234,21 +292,31 @@ fn gen_server_router(trait_name: &syn::Ident, methods: &[MethodCall]) -> proc_ma
             };
             use std::{future::Future, pin::Pin, sync::Arc};
 
+            /// Methods which are routed to a dedicated handler of the trait or the service.
+            pub(crate) const METHODS: &[&str] = &[#(#rpc_names,)* "$/cancelRequest", "$/setTrace", "exit"];
+
+            /// Methods among [`METHODS`] which are requests, and so are only routed when they have an ID.
+            const REQUEST_METHODS: &[&str] = &[#(#request_names,)*];
+
             /// A client-to-server LSP request.
-            #[derive(Clone, Debug, PartialEq, serde::Deserialize)]
-            #[cfg_attr(test, derive(serde::Serialize))]
+            ///
+            /// The parameters are kept as raw JSON until the request is dispatched, so that they
+            /// can be inspected and rewritten by middleware.
+            #[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
             pub struct ServerRequest {
                 jsonrpc: Version,
-                #[serde(flatten)]
-                kind: RequestKind,
+                method: String,
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                id: Option<Id>,
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                params: Option<serde_json::Value>,
             }
 
-            #[derive(Clone, Debug, PartialEq, serde::Deserialize)]
-            #[cfg_attr(test, derive(serde::Serialize))]
-            #[serde(untagged)]
+            #[derive(Debug)]
             enum RequestKind {
                 Known(ServerMethod),
                 Other { id: Option<Id>, method: String, params: Option<serde_json::Value> },
+                Invalid { id: Option<Id>, error: serde_json::Error },
             }
 
             #[derive(Clone, Debug, PartialEq, serde::Deserialize)]
@@ -257,37 +325,77 @@ fn gen_server_router(trait_name: &syn::Ident, methods: &[MethodCall]) -> proc_ma
             enum ServerMethod {
                 #variants
                 #[serde(rename = "$/cancelRequest")]
-                CancelRequest { id: Id },
+                CancelRequest { params: Params<CancelParams> },
+                #[serde(rename = "$/setTrace")]
+                SetTrace { params: Params<SetTraceParams> },
                 #[serde(rename = "exit")]
                 Exit,
             }
 
-            impl ServerMethod {
-                fn id(&self) -> Option<&Id> {
-                    match *self {
-                        #id_match_arms
-                        _ => None,
-                    }
-                }
+            #[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+            #[cfg_attr(test, derive(serde::Serialize))]
+            struct CancelParams {
+                id: Id,
             }
 
-            #[derive(Clone, Debug, PartialEq)]
+            #[derive(Clone, Debug, PartialEq, serde::Deserialize)]
             #[cfg_attr(test, derive(serde::Serialize))]
-            enum Params<T> {
-                Valid(T),
-                #[cfg_attr(test, serde(skip_serializing))]
-                Invalid(String),
+            struct SetTraceParams {
+                value: TraceOption,
+            }
+
+            impl ServerRequest {
+                /// Creates a request from its parts. A request without an ID is a notification.
+                pub(crate) fn new(method: String, id: Option<Id>, params: Option<serde_json::Value>) -> Self {
+                    ServerRequest { jsonrpc: Version, method, id, params }
+                }
+
+                /// Returns the method name of the request.
+                pub(crate) fn method(&self) -> &str {
+                    &self.method
+                }
+
+                /// Returns the ID of the request, or `None` if it is a notification.
+                pub(crate) fn id(&self) -> Option<&Id> {
+                    self.id.as_ref()
+                }
+
+                /// Returns the raw parameters of the request, if any.
+                pub(crate) fn params(&self) -> Option<&serde_json::Value> {
+                    self.params.as_ref()
+                }
+
+                /// Parses the parameters into the type expected by the handler for the method.
+                fn into_kind(self) -> RequestKind {
+                    use serde::{de::value::MapDeserializer, Deserialize};
+                    use serde_json::Value;
+
+                    let ServerRequest { method, id, params, .. } = self;
+                    let routed = METHODS.contains(&method.as_str())
+                        && (id.is_some() || !REQUEST_METHODS.contains(&method.as_str()));
+                    if !routed {
+                        return RequestKind::Other { id, method, params };
+                    }
+
+                    let mut fields = vec![("method", Value::String(method))];
+                    match &id {
+                        Some(Id::Number(n)) => fields.push(("id", Value::from(*n))),
+                        Some(Id::String(s)) => fields.push(("id", Value::from(s.as_str()))),
+                        None => {},
+                    }
+                    fields.extend(params.map(|params| ("params", params)));
+                    match ServerMethod::deserialize(MapDeserializer::new(fields.into_iter())) {
+                        Ok(method) => RequestKind::Known(method),
+                        Err(error) => RequestKind::Invalid { id, error },
+                    }
+                }
             }
 
-            impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Params<T> {
-                fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-                where
-                    D: serde::Deserializer<'de>,
-                {
-                    match serde::Deserialize::deserialize(deserializer) {
-                        Ok(Some(v)) => Ok(Params::Valid(v)),
-                        Ok(None) => Ok(Params::Invalid("Missing params field".to_string())),
-                        Err(e) => Ok(Params::Invalid(e.to_string())),
+            impl ServerMethod {
+                fn id(&self) -> Option<&Id> {
+                    match *self {
+                        #id_match_arms
+                        _ => None,
                     }
                 }
             }
@@ -295,30 +403,53 @@ fn gen_server_router(trait_name: &syn::Ident, methods: &[MethodCall]) -> proc_ma
             pub(crate) fn handle_request<T: #trait_name>(
                 server: T,
                 state: &Arc<State>,
+                session: &Arc<Session>,
                 pending: &ServerRequests,
-                request: Box<ServerRequest>,
+                custom: &CustomMethods,
+                documents: Option<&DocumentStore>,
+                request: ServerRequest,
             ) -> Pin<Box<dyn Future<Output = Result<Option<Outgoing>, ExitedError>> + Send>> {
                 use Params::*;
 
-                let method = match request.kind {
+                let method = match request.into_kind() {
                     RequestKind::Known(method) => method,
+                    RequestKind::Other { id, method, params } if custom.contains(&method) => {
+                        return custom.handle(state, pending, &method, id, params);
+                    }
                     RequestKind::Other { id: Some(id), method, params } => {
+                       let name = method.clone();
                        return pending
-                            .execute(id, async move { server.request_else(&method, params).await })
+                            .execute(id, &name, async move { server.request_else(&method, params).await })
                             .map(|v| Ok(Some(Outgoing::Response(v))))
                             .boxed();
                     }
-                    RequestKind::Other { id: None, method, .. } if !method.starts_with("$/") => {
-                        error!("method {:?} not found", method);
-                        return future::ok(None).boxed();
+                    RequestKind::Other { id: None, method, params } if state.get() == StateKind::Initialized => {
+                        return Box::pin(async move { server.notification_else(&method, params).await; Ok(None) });
                     }
                     RequestKind::Other { id: None, .. } => return future::ok(None).boxed(),
+                    RequestKind::Invalid { id, error } => {
+                        warn!("failed to parse request: {}", error);
+                        let res = id.map(|id| Outgoing::Response(Response::error(Some(id), Error::invalid_request())));
+                        return future::ok(res).boxed();
+                    }
                 };
 
                 match (method, state.get()) {
                     #route_match_arms
-                    (ServerMethod::CancelRequest { id }, StateKind::Initialized) => {
-                        pending.cancel(&id);
+                    (ServerMethod::CancelRequest { params: Valid(p) }, StateKind::Initialized) => {
+                        pending.cancel(&p.id);
+                        future::ok(None).boxed()
+                    }
+                    (ServerMethod::CancelRequest { .. }, StateKind::Initialized) => {
+                        warn!("invalid parameters for \"$/cancelRequest\" notification");
+                        future::ok(None).boxed()
+                    }
+                    (ServerMethod::SetTrace { params: Valid(p) }, StateKind::Initialized) => {
+                        session.set_trace(p.value);
+                        future::ok(None).boxed()
+                    }
+                    (ServerMethod::SetTrace { .. }, StateKind::Initialized) => {
+                        warn!("invalid parameters for \"$/setTrace\" notification");
                         future::ok(None).boxed()
                     }
                     (ServerMethod::Exit, _) => {
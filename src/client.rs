@@ -1,5 +1,8 @@
 //! Types for sending data to and from the language client.
 
+mod progress;
+
+pub use self::progress::Progress;
 use futures::{
     channel::{mpsc, oneshot},
     future::{self, Shared},
@@ -8,6 +11,7 @@ use futures::{
     FutureExt,
 };
 use std::{
+    cell::RefCell,
     convert::TryFrom,
     fmt::{self, Debug, Formatter},
     future::Future,
@@ -15,8 +19,12 @@ use std::{
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
+        RwLock,
     },
+    task::{Context, Poll},
+    time::Duration,
 };
+use thiserror::Error;
 
 type TokenFuture = Shared<Pin<Box<dyn Future<Output = Result<(), oneshot::Canceled>> + Send>>>;
 
@@ -101,11 +109,129 @@ impl Default for CancellationToken {
     }
 }
 
+thread_local! {
+    static CURRENT_REQUEST: RefCell<Option<(CancellationToken, Arc<AtomicBool>)>> = const { RefCell::new(None) };
+}
+
+impl CancellationToken {
+    /// Returns the cancellation token of the request whose handler is currently being polled.
+    ///
+    /// Returns `None` when called outside of a request handler, e.g. in a notification handler.
+    /// The token is stored in a thread-local variable which is only set while the handler itself
+    /// is polled, so this also returns `None` inside `spawn_blocking` closures or tasks spawned by
+    /// the handler. Call it in the handler before spawning such work, and move the token into it:
+    ///
+    /// ```rust,ignore
+    /// let token = CancellationToken::current().unwrap();
+    /// let result = tokio::task::spawn_blocking(move || analyze(&token)).await;
+    /// ```
+    ///
+    /// Calling this opts the request into cooperative cancellation: when the client sends
+    /// `$/cancelRequest`, the token is signalled and the handler is given a grace period to clean
+    /// up and return, typically with [`Error::request_cancelled`]. If it is still running
+    /// afterwards, it is aborted like handlers which never ask for their token, which are aborted
+    /// as soon as the cancellation arrives. Requests which time out are cancelled the same way.
+    /// The grace period is set with [`LspServiceBuilder::cancellation_grace_period`].
+    ///
+    /// [`Error::request_cancelled`]: crate::jsonrpc::Error::request_cancelled
+    /// [`LspServiceBuilder::cancellation_grace_period`]: crate::LspServiceBuilder::cancellation_grace_period
+    pub fn current() -> Option<CancellationToken> {
+        CURRENT_REQUEST.with(|current| {
+            current.borrow().as_ref().map(|(token, cooperative)| {
+                cooperative.store(true, Ordering::SeqCst);
+                token.clone()
+            })
+        })
+    }
+}
+
+/// Future which exposes a request's [`CancellationToken`] through [`CancellationToken::current`]
+/// while the wrapped request handler is being polled.
+pub(crate) struct RequestScope<F> {
+    token: CancellationToken,
+    cooperative: Arc<AtomicBool>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> RequestScope<F> {
+    /// Wraps `future`, setting `cooperative` once the handler has asked for its token.
+    pub(crate) fn new(token: CancellationToken, cooperative: Arc<AtomicBool>, future: F) -> Self {
+        RequestScope {
+            token,
+            cooperative,
+            future: Box::pin(future),
+        }
+    }
+}
+
+impl<F: Future> Future for RequestScope<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        struct Restore(Option<(CancellationToken, Arc<AtomicBool>)>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                CURRENT_REQUEST.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        let this = &mut *self;
+        let scope = Some((this.token.clone(), this.cooperative.clone()));
+        let _restore = Restore(CURRENT_REQUEST.with(|current| current.replace(scope)));
+        this.future.as_mut().poll(cx)
+    }
+}
+
+/// Error returned by the requests a [`Client`] sends to the language client.
+#[derive(Clone, Debug, PartialEq, Error)]
+#[non_exhaustive]
+pub enum ClientError {
+    /// The client answered the request with an error, or the request could not be sent.
+    #[error(transparent)]
+    Rpc(crate::jsonrpc::Error),
+    /// The request was not sent because the client did not advertise support for it.
+    #[error("client does not support {0:?}")]
+    Unsupported(&'static str),
+    /// The client did not answer the request before its timeout elapsed.
+    #[error("{method:?} request timed out after {timeout:?}")]
+    TimedOut {
+        /// The method of the request.
+        method: &'static str,
+        /// How long the request waited for a response.
+        timeout: Duration,
+    },
+}
+
+impl From<crate::jsonrpc::Error> for ClientError {
+    fn from(error: crate::jsonrpc::Error) -> Self {
+        ClientError::Rpc(error)
+    }
+}
+
+/// Converts the error into a JSON-RPC error, so that `?` can be used on client requests in
+/// request handlers. Errors which did not come from the client become internal errors.
+impl From<ClientError> for crate::jsonrpc::Error {
+    fn from(error: ClientError) -> Self {
+        match error {
+            ClientError::Rpc(error) => error,
+            error => crate::jsonrpc::Error {
+                code: crate::jsonrpc::ErrorCode::InternalError,
+                message: error.to_string(),
+                data: None,
+            },
+        }
+    }
+}
+
 struct ClientInner {
     sender: mpsc::Sender<crate::jsonrpc::Outgoing>,
     request_id: AtomicU64,
     pending_requests: Arc<crate::jsonrpc::ClientRequests>,
     state: Arc<crate::server::State>,
+    session: Arc<crate::server::Session>,
+    default_timeout: RwLock<Option<Duration>>,
 }
 
 /// Handle for communicating with the language client.
@@ -117,6 +243,7 @@ struct ClientInner {
 #[derive(Clone)]
 pub struct Client {
     inner: Arc<ClientInner>,
+    timeout: Option<Duration>,
 }
 
 impl Client {
@@ -124,6 +251,7 @@ impl Client {
         sender: mpsc::Sender<crate::jsonrpc::Outgoing>,
         pending_requests: Arc<crate::jsonrpc::ClientRequests>,
         state: Arc<crate::server::State>,
+        session: Arc<crate::server::Session>,
     ) -> Self {
         Client {
             inner: Arc::new(ClientInner {
@@ -131,10 +259,56 @@ impl Client {
                 request_id: AtomicU64::new(0),
                 pending_requests,
                 state,
+                session,
+                default_timeout: RwLock::new(None),
             }),
+            timeout: None,
+        }
+    }
+
+    /// Sets how long requests sent to the client may wait for a response by default.
+    ///
+    /// The default applies to every handle cloned from this `Client` which has no timeout of its
+    /// own (see [`with_timeout`]). Passing `None`, which is the initial value, waits forever.
+    ///
+    /// A request which times out is removed from the pending requests, the client is sent a
+    /// `$/cancelRequest` notification, and the request returns [`ClientError::TimedOut`].
+    ///
+    /// [`with_timeout`]: Client::with_timeout
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        *self.inner.default_timeout.write().unwrap() = timeout;
+    }
+
+    /// Returns a handle whose requests time out after `timeout`, overriding the default.
+    ///
+    /// ```rust,ignore
+    /// let applied = client.with_timeout(Duration::from_secs(5)).apply_edit(edit, None).await?;
+    /// ```
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Client {
+            inner: self.inner.clone(),
+            timeout: Some(timeout),
         }
     }
 
+    /// Returns the capabilities the client advertised in its `initialize` request.
+    ///
+    /// Returns `None` if the server has not been initialized yet.
+    pub fn capabilities(&self) -> Option<lsp::ClientCapabilities> {
+        self.inner.session.capabilities()
+    }
+
+    /// Returns the position encoding negotiated with the client during initialization.
+    ///
+    /// This is [`PositionEncoding::Utf16`] unless both the server and the client support another
+    /// encoding. See [`LspServiceBuilder::position_encodings`].
+    ///
+    /// [`PositionEncoding::Utf16`]: crate::PositionEncoding::Utf16
+    /// [`LspServiceBuilder::position_encodings`]: crate::LspServiceBuilder::position_encodings
+    pub fn position_encoding(&self) -> crate::PositionEncoding {
+        self.inner.session.position_encoding()
+    }
+
     /// Notifies the client to log a particular message.
     ///
     /// This corresponds to the [`window/logMessage`] notification.
@@ -146,6 +320,28 @@ impl Client {
         self.send_notification::<lsp::notification::LogMessage>(params).await;
     }
 
+    /// Notifies the client to log a trace of the server's execution.
+    ///
+    /// This corresponds to the [`$/logTrace`] notification. It is only sent if the client enabled
+    /// tracing, either in the `initialize` request or with a later `$/setTrace` notification. The
+    /// `verbose` text is dropped unless the trace level is `verbose`.
+    ///
+    /// [`$/logTrace`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#logTrace
+    ///
+    /// # Compatibility
+    ///
+    /// This notification was introduced in specification version 3.16.0.
+    pub async fn log_trace<M: std::fmt::Display>(&self, message: M, verbose: Option<String>) {
+        let verbose = match self.inner.session.trace() {
+            lsp::TraceOption::Off => return,
+            lsp::TraceOption::Messages => None,
+            lsp::TraceOption::Verbose => verbose,
+        };
+        let message = message.to_string();
+        let params = LogTraceParams { message, verbose };
+        self.send_notification::<LogTrace>(params).await;
+    }
+
     /// Notifies the client to display a particular message in the user interface.
     ///
     /// This corresponds to the [`window/showMessage`] notification.
@@ -171,13 +367,43 @@ impl Client {
         typ: lsp::MessageType,
         message: M,
         actions: Option<Vec<lsp::MessageActionItem>>,
-    ) -> crate::jsonrpc::Result<Option<lsp::MessageActionItem>> {
+    ) -> Result<Option<lsp::MessageActionItem>, ClientError> {
         let token = CancellationToken::default();
         let message = message.to_string();
         let params = lsp::ShowMessageRequestParams { typ, message, actions };
         self.send_request::<lsp::request::ShowMessageRequest>(params, token).await
     }
 
+    /// Asks the client to display a particular resource referenced by a URI in the user interface.
+    ///
+    /// Returns `true` if the client successfully showed the document.
+    ///
+    /// This corresponds to the [`window/showDocument`] request.
+    ///
+    /// [`window/showDocument`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#window_showDocument
+    ///
+    /// # Initialization
+    ///
+    /// If the request is sent to client before the server has been initialized, this will
+    /// immediately return `Err` with JSON-RPC error code `-32002` ([read more]).
+    ///
+    /// [read more]: https://microsoft.github.io/language-server-protocol/specification#initialize
+    ///
+    /// If the client did not advertise support for this request in its capabilities, this will
+    /// immediately return [`ClientError::Unsupported`] instead of sending it.
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.16.0.
+    pub async fn show_document(&self, params: lsp::ShowDocumentParams) -> Result<bool, ClientError> {
+        let token = CancellationToken::default();
+        let supported = |c: &lsp::ClientCapabilities| Some(c.window.as_ref()?.show_document.as_ref()?.support);
+        let result = self
+            .send_request_supported::<lsp::request::ShowDocument>(params, token, supported)
+            .await?;
+        Ok(result.success)
+    }
+
     /// Notifies the client to log a telemetry event.
     ///
     /// This corresponds to the [`telemetry/event`] notification.
@@ -208,7 +434,7 @@ impl Client {
     ///
     /// [read more]: https://microsoft.github.io/language-server-protocol/specification#initialize
     #[rustfmt::skip]
-    pub async fn register_capability(&self, registrations: Vec<lsp::Registration>) -> crate::jsonrpc::Result<()> {
+    pub async fn register_capability(&self, registrations: Vec<lsp::Registration>) -> Result<(), ClientError> {
         let token = CancellationToken::default();
         let params = lsp::RegistrationParams { registrations };
         self.send_request_initialized::<lsp::request::RegisterCapability>(params, token).await
@@ -230,7 +456,7 @@ impl Client {
     pub async fn unregister_capability(
         &self,
         unregisterations: Vec<lsp::Unregistration>,
-    ) -> crate::jsonrpc::Result<()> {
+    ) -> Result<(), ClientError> {
         let token = CancellationToken::default();
         let params = lsp::UnregistrationParams { unregisterations };
         self.send_request_initialized::<lsp::request::UnregisterCapability>(params, token).await
@@ -252,13 +478,17 @@ impl Client {
     ///
     /// [read more]: https://microsoft.github.io/language-server-protocol/specification#initialize
     ///
+    /// If the client did not advertise support for this request in its capabilities, this will
+    /// immediately return [`ClientError::Unsupported`] instead of sending it.
+    ///
     /// # Compatibility
     ///
     /// This request was introduced in specification version 3.6.0.
     #[rustfmt::skip]
-    pub async fn workspace_folders(&self) -> crate::jsonrpc::Result<Option<Vec<lsp::WorkspaceFolder>>> {
+    pub async fn workspace_folders(&self) -> Result<Option<Vec<lsp::WorkspaceFolder>>, ClientError> {
         let token = CancellationToken::default();
-        self.send_request_initialized::<lsp::request::WorkspaceFoldersRequest>((), token).await
+        let supported = |c: &lsp::ClientCapabilities| c.workspace.as_ref()?.workspace_folders;
+        self.send_request_supported::<lsp::request::WorkspaceFoldersRequest>((), token, supported).await
     }
 
     /// Fetches configuration settings from the client.
@@ -281,6 +511,9 @@ impl Client {
     ///
     /// [read more]: https://microsoft.github.io/language-server-protocol/specification#initialize
     ///
+    /// If the client did not advertise support for this request in its capabilities, this will
+    /// immediately return [`ClientError::Unsupported`] instead of sending it.
+    ///
     /// # Compatibility
     ///
     /// This request was introduced in specification version 3.6.0.
@@ -288,10 +521,11 @@ impl Client {
     pub async fn configuration(
         &self,
         items: Vec<lsp::ConfigurationItem>,
-    ) -> crate::jsonrpc::Result<Vec<serde_json::Value>> {
+    ) -> Result<Vec<serde_json::Value>, ClientError> {
         let token = CancellationToken::default();
         let params = lsp::ConfigurationParams { items };
-        self.send_request_initialized::<lsp::request::WorkspaceConfiguration>(params, token).await
+        let supported = |c: &lsp::ClientCapabilities| c.workspace.as_ref()?.configuration;
+        self.send_request_supported::<lsp::request::WorkspaceConfiguration>(params, token, supported).await
     }
 
     /// Requests a workspace resource be edited on the client side and returns whether the edit was
@@ -307,15 +541,77 @@ impl Client {
     /// immediately return `Err` with JSON-RPC error code `-32002` ([read more]).
     ///
     /// [read more]: https://microsoft.github.io/language-server-protocol/specification#initialize
+    ///
+    /// If the client did not advertise support for this request in its capabilities, this will
+    /// immediately return [`ClientError::Unsupported`] instead of sending it.
     #[rustfmt::skip]
     pub async fn apply_edit(
         &self,
         edit: lsp::WorkspaceEdit,
         label: Option<String>,
-    ) -> crate::jsonrpc::Result<lsp::ApplyWorkspaceEditResponse> {
+    ) -> Result<lsp::ApplyWorkspaceEditResponse, ClientError> {
         let token = CancellationToken::default();
         let params = lsp::ApplyWorkspaceEditParams { label, edit };
-        self.send_request_initialized::<lsp::request::ApplyWorkspaceEdit>(params, token).await
+        let supported = |c: &lsp::ClientCapabilities| c.workspace.as_ref()?.apply_edit;
+        self.send_request_supported::<lsp::request::ApplyWorkspaceEdit>(params, token, supported).await
+    }
+
+    /// Asks the client to refresh all code lenses currently shown in editors.
+    ///
+    /// The server should only use this if the global workspace state changed in a way that
+    /// requires the client to request code lenses again, e.g. after a project-wide configuration
+    /// change.
+    ///
+    /// This corresponds to the [`workspace/codeLens/refresh`] request.
+    ///
+    /// [`workspace/codeLens/refresh`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#codeLens_refresh
+    ///
+    /// # Initialization
+    ///
+    /// If the request is sent to client before the server has been initialized, this will
+    /// immediately return `Err` with JSON-RPC error code `-32002` ([read more]).
+    ///
+    /// [read more]: https://microsoft.github.io/language-server-protocol/specification#initialize
+    ///
+    /// If the client did not advertise support for this request in its capabilities, this will
+    /// immediately return [`ClientError::Unsupported`] instead of sending it.
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.16.0.
+    pub async fn code_lens_refresh(&self) -> Result<(), ClientError> {
+        let token = CancellationToken::default();
+        let supported = |c: &lsp::ClientCapabilities| c.workspace.as_ref()?.code_lens.as_ref()?.refresh_support;
+        self.send_request_supported::<lsp::request::CodeLensRefresh>((), token, supported).await
+    }
+
+    /// Asks the client to refresh all semantic tokens currently shown in editors.
+    ///
+    /// The server should only use this if the global workspace state changed in a way that
+    /// requires the client to request semantic tokens again, e.g. after a project-wide
+    /// configuration change.
+    ///
+    /// This corresponds to the [`workspace/semanticTokens/refresh`] request.
+    ///
+    /// [`workspace/semanticTokens/refresh`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#semanticTokens_refreshRequest
+    ///
+    /// # Initialization
+    ///
+    /// If the request is sent to client before the server has been initialized, this will
+    /// immediately return `Err` with JSON-RPC error code `-32002` ([read more]).
+    ///
+    /// [read more]: https://microsoft.github.io/language-server-protocol/specification#initialize
+    ///
+    /// If the client did not advertise support for this request in its capabilities, this will
+    /// immediately return [`ClientError::Unsupported`] instead of sending it.
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.16.0.
+    pub async fn semantic_tokens_refresh(&self) -> Result<(), ClientError> {
+        let token = CancellationToken::default();
+        let supported = |c: &lsp::ClientCapabilities| c.workspace.as_ref()?.semantic_tokens.as_ref()?.refresh_support;
+        self.send_request_supported::<lsp::request::SemanticTokensRefesh>((), token, supported).await
     }
 
     /// Submits validation diagnostics for an open file with the given URI.
@@ -333,6 +629,100 @@ impl Client {
         self.send_notification_initialized::<lsp::notification::PublishDiagnostics>(params).await;
     }
 
+    /// Starts reporting server-initiated work done progress to the client.
+    ///
+    /// This asks the client to create a progress indicator for `token` with the
+    /// [`window/workDoneProgress/create`] request, then begins it with the given `title` by
+    /// sending a [`$/progress`] notification. The returned [`Progress`] handle reports further
+    /// updates on the same token.
+    ///
+    /// [`window/workDoneProgress/create`]: https://microsoft.github.io/language-server-protocol/specification#window_workDoneProgress_create
+    /// [`$/progress`]: https://microsoft.github.io/language-server-protocol/specification#progress
+    ///
+    /// If the client did not advertise the `window.workDoneProgress` capability, or if it rejects
+    /// the token, the returned handle silently does nothing.
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.15.0.
+    pub async fn progress<T: std::fmt::Display>(&self, token: lsp::ProgressToken, title: T) -> Progress {
+        if !self.inner.session.supports_work_done_progress() {
+            log::trace!("client does not support work done progress, ignoring token: {:?}", token);
+            return Progress::noop(self.clone());
+        }
+
+        let created = {
+            let params = lsp::WorkDoneProgressCreateParams { token: token.clone() };
+            let token = CancellationToken::default();
+            self.send_request_initialized::<lsp::request::WorkDoneProgressCreate>(params, token)
+                .await
+        };
+
+        match created {
+            Ok(()) => Progress::begin(self.clone(), token, title.to_string()).await,
+            Err(err) => {
+                log::warn!("failed to create work done progress token {:?}: {}", token, err);
+                Progress::noop(self.clone())
+            },
+        }
+    }
+
+    /// Starts reporting work done progress on a token supplied by the client.
+    ///
+    /// Many requests carry an optional `work_done_token` in their [`WorkDoneProgressParams`].
+    /// When present, the server may report progress on it without creating it first. If the
+    /// client did not supply a token, the returned handle silently does nothing.
+    ///
+    /// [`WorkDoneProgressParams`]: lsp::WorkDoneProgressParams
+    ///
+    /// # Compatibility
+    ///
+    /// Work done progress was introduced in specification version 3.15.0.
+    pub async fn attach_progress<T: std::fmt::Display>(
+        &self,
+        params: &lsp::WorkDoneProgressParams,
+        title: T,
+    ) -> Progress {
+        match &params.work_done_token {
+            Some(token) => Progress::begin(self.clone(), token.clone(), title.to_string()).await,
+            None => Progress::noop(self.clone()),
+        }
+    }
+
+    /// Streams a batch of partial results for a request back to the client.
+    ///
+    /// Requests such as `textDocument/references` or `workspace/symbol` may carry a
+    /// `partial_result_token` in their [`PartialResultParams`]. When present, the handler can
+    /// send its results in batches with this method, each as a [`$/progress`] notification on
+    /// that token. The value should have the same type as the request result (e.g.
+    /// `Vec<lsp::Location>`).
+    ///
+    /// [`PartialResultParams`]: lsp::PartialResultParams
+    /// [`$/progress`]: https://microsoft.github.io/language-server-protocol/specification#progress
+    ///
+    /// Once all batches are sent, the handler must still return a result. The specification
+    /// requires it to be empty when partial results were reported. Each batch is queued before
+    /// this method returns, and `Server::serve` writes up to 64 queued messages before a ready
+    /// response, so the batches are delivered before the final response unless more than 64
+    /// messages were queued ahead of it.
+    ///
+    /// # Initialization
+    ///
+    /// This notification will only be sent if the server is initialized.
+    ///
+    /// # Compatibility
+    ///
+    /// Partial results were introduced in specification version 3.15.0.
+    pub async fn send_partial_result<T: serde::Serialize>(&self, token: lsp::ProgressToken, value: T) {
+        match serde_json::to_value(value) {
+            Err(e) => log::error!("invalid JSON in partial result: {}", e),
+            Ok(value) => {
+                let params = progress::PartialResultParams { token, value };
+                self.send_notification_initialized::<progress::PartialResult>(params).await;
+            },
+        }
+    }
+
     /// Sends a custom notification to the client.
     ///
     /// # Initialization
@@ -377,14 +767,14 @@ impl Client {
         &self,
         params: R::Params,
         token: CancellationToken,
-    ) -> crate::jsonrpc::Result<R::Result>
+    ) -> Result<R::Result, ClientError>
     where
         R: lsp::request::Request,
     {
         self.send_request_initialized::<R>(params, token).await
     }
 
-    async fn send_request<R>(&self, params: R::Params, token: CancellationToken) -> crate::jsonrpc::Result<R::Result>
+    async fn send_request<R>(&self, params: R::Params, token: CancellationToken) -> Result<R::Result, ClientError>
     where
         R: lsp::request::Request,
     {
@@ -395,31 +785,72 @@ impl Client {
 
         if self.inner.sender.clone().send(message).await.is_err() {
             log::error!("failed to send request");
-            return Err(crate::jsonrpc::Error::internal_error());
+            return Err(crate::jsonrpc::Error::internal_error().into());
         }
 
+        let timeout = self.timeout.or(*self.inner.default_timeout.read().unwrap());
+        let timer = async move {
+            match timeout {
+                Some(duration) => crate::time::sleep(duration).await,
+                None => future::pending().await,
+            }
+        };
+        futures::pin_mut!(timer);
+
         select! {
             _ = token.wait() => {
-                if self.inner.pending_requests.0.remove(&crate::jsonrpc::Id::Number(id)).is_none() {
-                    log::warn!("received response with unknown request ID: {}", id);
-                }
-                let params = {
-                    let id = i32::try_from(id).expect("error converting u64 to i32");
-                    lsp::CancelParams { id: lsp::NumberOrString::Number(id) }
-                };
-                self.send_notification::<lsp::notification::Cancel>(params).await;
-                Err(crate::jsonrpc::Error::request_cancelled())
+                self.abandon_request(id).await;
+                Err(crate::jsonrpc::Error::request_cancelled().into())
+            },
+            _ = timer.fuse() => {
+                let timeout = timeout.unwrap_or_default();
+                log::warn!("{} request timed out after {:?}", R::METHOD, timeout);
+                self.abandon_request(id).await;
+                Err(ClientError::TimedOut { method: R::METHOD, timeout })
             },
             response = response_waiter.fuse() => {
                 let (_, result) = response.into_parts();
-                result.and_then(|v| {
+                let result = result.and_then(|v| {
                     serde_json::from_value(v).map_err(|e| crate::jsonrpc::Error {
                         code: crate::jsonrpc::ErrorCode::ParseError,
                         message: e.to_string(),
                         data: None,
                     })
-                })
+                });
+                result.map_err(ClientError::Rpc)
+            },
+        }
+    }
+
+    /// Stops waiting for the request with the given ID and asks the client to cancel it.
+    async fn abandon_request(&self, id: u64) {
+        if self.inner.pending_requests.0.remove(&crate::jsonrpc::Id::Number(id)).is_none() {
+            log::warn!("received response with unknown request ID: {}", id);
+        }
+        let params = {
+            let id = i32::try_from(id).expect("error converting u64 to i32");
+            lsp::CancelParams { id: lsp::NumberOrString::Number(id) }
+        };
+        self.send_notification::<lsp::notification::Cancel>(params).await;
+    }
+
+    async fn send_request_supported<R>(
+        &self,
+        params: R::Params,
+        token: CancellationToken,
+        supported: fn(&lsp::ClientCapabilities) -> Option<bool>,
+    ) -> Result<R::Result, ClientError>
+    where
+        R: lsp::request::Request,
+    {
+        match self.inner.state.get() {
+            crate::server::StateKind::Initialized | crate::server::StateKind::ShutDown
+                if !self.inner.session.supports(supported) =>
+            {
+                log::trace!("client does not support {:?}, supressing request", R::METHOD);
+                Err(ClientError::Unsupported(R::METHOD))
             },
+            _ => self.send_request_initialized::<R>(params, token).await,
         }
     }
 
@@ -427,7 +858,7 @@ impl Client {
         &self,
         params: R::Params,
         token: CancellationToken,
-    ) -> crate::jsonrpc::Result<R::Result>
+    ) -> Result<R::Result, ClientError>
     where
         R: lsp::request::Request,
     {
@@ -437,7 +868,7 @@ impl Client {
             let id = self.inner.request_id.load(Ordering::SeqCst) + 1;
             let msg = crate::jsonrpc::ClientRequest::request::<R>(id, params);
             log::trace!("server not initialized, supressing message: {}", msg);
-            Err(crate::jsonrpc::not_initialized_error())
+            Err(crate::jsonrpc::not_initialized_error().into())
         }
     }
 }
@@ -448,10 +879,26 @@ impl Debug for Client {
             .field("request_id", &self.inner.request_id)
             .field("pending_requests", &self.inner.pending_requests)
             .field("state", &self.inner.state)
+            .field("timeout", &self.timeout)
             .finish()
     }
 }
 
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct LogTraceParams {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verbose: Option<String>,
+}
+
+enum LogTrace {}
+
+impl lsp::notification::Notification for LogTrace {
+    type Params = LogTraceParams;
+
+    const METHOD: &'static str = "$/logTrace";
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -483,13 +930,23 @@ mod tests {
             pub(super) fn client(initialize: bool) -> (Client, mpsc::Receiver<Outgoing>) {
                 let state = Arc::new(crate::server::State::new());
                 let (tx, rx) = mpsc::channel(4);
+                let session = Arc::new(crate::server::Session::new());
                 let pending_client = Arc::new(crate::jsonrpc::ClientRequests::new());
-                let client = crate::client::Client::new(tx, pending_client, state);
+                let client = crate::client::Client::new(tx, pending_client, state, session);
                 if initialize {
                     client.inner.state.set(crate::server::StateKind::Initialized);
                 }
                 (client, rx)
             }
+
+            pub(super) fn client_with_capabilities(
+                capabilities: serde_json::Value,
+            ) -> (Client, mpsc::Receiver<Outgoing>) {
+                let (client, rx) = client(true);
+                let params = serde_json::from_value(serde_json::json!({ "capabilities": capabilities })).unwrap();
+                client.inner.session.initialize(&params);
+                (client, rx)
+            }
         }
 
         #[tokio::test]
@@ -512,11 +969,41 @@ mod tests {
                 client.inner.pending_requests.insert(Response::ok(id, result));
             };
             let (result, ()) = futures::future::join(req, rsp).await;
-            assert_eq!(result, Err(crate::jsonrpc::not_initialized_error()));
+            assert_eq!(result, Err(crate::jsonrpc::not_initialized_error().into()));
 
             Ok(())
         }
 
+        #[tokio::test]
+        async fn code_lens_refresh() {
+            let capabilities = json!({ "workspace": { "codeLens": { "refreshSupport": true } } });
+            let (client, mut rx) = helper::client_with_capabilities(capabilities);
+
+            let req = client.code_lens_refresh();
+            let rsp = async {
+                let item = rx.next().await.unwrap();
+                let request = ClientRequest::request::<lsp::request::CodeLensRefresh>(0, ());
+                assert_eq!(item, Outgoing::Request(request));
+                client
+                    .inner
+                    .pending_requests
+                    .insert(Response::ok(Id::Number(0), json!(null)));
+            };
+            let (result, ()) = futures::future::join(req, rsp).await;
+            assert_eq!(result, Ok(()));
+        }
+
+        #[test]
+        fn capabilities() {
+            let client = helper::client(true).0;
+            assert_eq!(client.capabilities(), None);
+
+            let capabilities = json!({ "workspace": { "configuration": true } });
+            let client = helper::client_with_capabilities(capabilities.clone()).0;
+            let expected = serde_json::from_value(capabilities).unwrap();
+            assert_eq!(client.capabilities(), Some(expected));
+        }
+
         #[tokio::test]
         async fn configuration() -> anyhow::Result<()> {
             let (client, _rx) = helper::client(false);
@@ -531,11 +1018,33 @@ mod tests {
                 client.inner.pending_requests.insert(Response::ok(id, result));
             };
             let (result, ()) = futures::future::join(req, rsp).await;
-            assert_eq!(result, Err(crate::jsonrpc::not_initialized_error()));
+            assert_eq!(result, Err(crate::jsonrpc::not_initialized_error().into()));
 
             Ok(())
         }
 
+        #[tokio::test]
+        async fn configuration_unsupported() {
+            let capabilities = json!({ "workspace": { "configuration": false } });
+            let (client, mut rx) = helper::client_with_capabilities(capabilities);
+            let result = client.configuration(vec![]).await;
+            let method = <lsp::request::WorkspaceConfiguration as lsp::request::Request>::METHOD;
+            assert_eq!(result, Err(ClientError::Unsupported(method)));
+
+            drop(client);
+            assert_eq!(rx.next().await, None);
+        }
+
+        #[test]
+        fn client_error_into_jsonrpc_error() {
+            let error = crate::jsonrpc::Error::request_cancelled();
+            assert_eq!(crate::jsonrpc::Error::from(ClientError::from(error.clone())), error);
+
+            let error = crate::jsonrpc::Error::from(ClientError::Unsupported("workspace/configuration"));
+            assert_eq!(error.code, crate::jsonrpc::ErrorCode::InternalError);
+            assert_eq!(error.message, "client does not support \"workspace/configuration\"");
+        }
+
         #[test]
         fn display() {
             let client = helper::client(true).0;
@@ -561,6 +1070,145 @@ mod tests {
             }
         }
 
+        #[tokio::test]
+        async fn log_trace() {
+            let (client, mut rx) = helper::client(true);
+            client.log_trace("off", Some("details".into())).await;
+
+            client.inner.session.set_trace(lsp::TraceOption::Messages);
+            client.log_trace("messages", Some("details".into())).await;
+            let raw = json!({ "jsonrpc": "2.0", "method": "$/logTrace", "params": { "message": "messages" } });
+            assert_eq!(rx.next().await, Some(serde_json::from_value(raw).unwrap()));
+
+            client.inner.session.set_trace(lsp::TraceOption::Verbose);
+            client.log_trace("verbose", Some("details".into())).await;
+            let params = json!({ "message": "verbose", "verbose": "details" });
+            let raw = json!({ "jsonrpc": "2.0", "method": "$/logTrace", "params": params });
+            assert_eq!(rx.next().await, Some(serde_json::from_value(raw).unwrap()));
+        }
+
+        #[tokio::test]
+        async fn progress() {
+            let (client, mut rx) = helper::client(true);
+            let params = serde_json::from_value(json!({
+                "capabilities": { "window": { "workDoneProgress": true } },
+            }))
+            .unwrap();
+            client.inner.session.initialize(&params);
+
+            let token = lsp::ProgressToken::String("indexing".into());
+            let req = client.progress(token.clone(), "Indexing");
+            let rsp = async {
+                let item = rx.next().await.unwrap();
+                let params = lsp::WorkDoneProgressCreateParams { token: token.clone() };
+                let request = ClientRequest::request::<lsp::request::WorkDoneProgressCreate>(0, params);
+                assert_eq!(item, Outgoing::Request(request));
+                client.inner.pending_requests.insert(Response::ok(Id::Number(0), json!(null)));
+            };
+            let (progress, ()) = futures::future::join(req, rsp).await;
+            assert_eq!(progress.token(), Some(&token));
+
+            progress.report(Some(50), Some("halfway".into())).await;
+            progress.finish(None).await;
+
+            let values: Vec<_> = rx.take(3).collect().await;
+            let expected: Vec<_> = vec![
+                json!({ "kind": "begin", "title": "Indexing" }),
+                json!({ "kind": "report", "message": "halfway", "percentage": 50 }),
+                json!({ "kind": "end" }),
+            ]
+            .into_iter()
+            .map(|value| {
+                let value = lsp::ProgressParamsValue::WorkDone(serde_json::from_value(value).unwrap());
+                let params = lsp::ProgressParams {
+                    token: token.clone(),
+                    value,
+                };
+                Outgoing::Request(ClientRequest::notification::<lsp::notification::Progress>(params))
+            })
+            .collect();
+            assert_eq!(values, expected);
+        }
+
+        #[tokio::test]
+        async fn progress_unsupported() {
+            let (client, mut rx) = helper::client(true);
+            let params = serde_json::from_value(json!({ "capabilities": {} })).unwrap();
+            client.inner.session.initialize(&params);
+
+            let token = lsp::ProgressToken::Number(1);
+            let progress = client.progress(token, "Indexing").await;
+            assert_eq!(progress.token(), None);
+            progress.report(None, None).await;
+            progress.finish(None).await;
+
+            drop(client);
+            assert_eq!(rx.next().await, None);
+        }
+
+        #[tokio::test]
+        async fn attach_progress() {
+            let (client, mut rx) = helper::client(true);
+
+            let token = lsp::ProgressToken::Number(1);
+            let params = lsp::WorkDoneProgressParams {
+                work_done_token: Some(token.clone()),
+            };
+            let progress = client.attach_progress(&params, "Searching").await;
+            assert_eq!(progress.token(), Some(&token));
+
+            let begin = lsp::WorkDoneProgressBegin {
+                title: "Searching".into(),
+                ..Default::default()
+            };
+            let params = lsp::ProgressParams {
+                token,
+                value: lsp::ProgressParamsValue::WorkDone(lsp::WorkDoneProgress::Begin(begin)),
+            };
+            let message = Outgoing::Request(ClientRequest::notification::<lsp::notification::Progress>(params));
+            assert_eq!(rx.next().await, Some(message));
+
+            let params = lsp::WorkDoneProgressParams::default();
+            let progress = client.attach_progress(&params, "Searching").await;
+            assert_eq!(progress.token(), None);
+            progress.finish(None).await;
+        }
+
+        #[tokio::test]
+        async fn semantic_tokens_refresh() {
+            let capabilities = json!({ "workspace": { "semanticTokens": { "refreshSupport": true } } });
+            let (client, mut rx) = helper::client_with_capabilities(capabilities);
+
+            let req = client.semantic_tokens_refresh();
+            let rsp = async {
+                let item = rx.next().await.unwrap();
+                let request = ClientRequest::request::<lsp::request::SemanticTokensRefesh>(0, ());
+                assert_eq!(item, Outgoing::Request(request));
+                client
+                    .inner
+                    .pending_requests
+                    .insert(Response::ok(Id::Number(0), json!(null)));
+            };
+            let (result, ()) = futures::future::join(req, rsp).await;
+            assert_eq!(result, Ok(()));
+        }
+
+        #[tokio::test]
+        async fn send_partial_result() {
+            let (client, mut rx) = helper::client(true);
+            let token = lsp::ProgressToken::String("partial".into());
+            let location = lsp::Location::new(lsp::Url::parse("inmemory::///test").unwrap(), Default::default());
+            client.send_partial_result(token, vec![location.clone()]).await;
+            if let Some(item) = rx.next().await {
+                let raw = json!({
+                    "jsonrpc": "2.0",
+                    "method": "$/progress",
+                    "params": { "token": "partial", "value": [location] },
+                });
+                assert_eq!(item, serde_json::from_value(raw).unwrap());
+            }
+        }
+
         #[tokio::test]
         async fn publish_diagnostics() {
             let (client, mut rx) = helper::client(true);
@@ -706,10 +1354,10 @@ mod tests {
             let (result, ()) = futures::future::join(req, rsp).await;
             assert!(matches!(
                 result,
-                Err(crate::jsonrpc::Error {
+                Err(ClientError::Rpc(crate::jsonrpc::Error {
                     code: crate::jsonrpc::ErrorCode::ParseError,
                     ..
-                })
+                }))
             ));
         }
 
@@ -746,7 +1394,69 @@ mod tests {
             canceller.cancel();
 
             let (result, ()) = futures::future::join(req, rsp).await;
-            assert_eq!(result, Err(crate::jsonrpc::Error::request_cancelled()));
+            assert_eq!(result, Err(crate::jsonrpc::Error::request_cancelled().into()));
+        }
+
+        #[tokio::test]
+        async fn with_timeout() {
+            let capabilities = json!({ "workspace": { "applyEdit": true } });
+            let (client, mut rx) = helper::client_with_capabilities(capabilities);
+            client.set_default_timeout(Some(Duration::from_secs(3600)));
+
+            let edit = lsp::WorkspaceEdit::default();
+            let result = client
+                .with_timeout(Duration::from_millis(10))
+                .apply_edit(edit.clone(), None)
+                .await;
+            let method = <lsp::request::ApplyWorkspaceEdit as lsp::request::Request>::METHOD;
+            let timeout = Duration::from_millis(10);
+            assert_eq!(result, Err(ClientError::TimedOut { method, timeout }));
+            assert!(client.inner.pending_requests.0.is_empty());
+
+            let params = lsp::ApplyWorkspaceEditParams { label: None, edit };
+            let request = ClientRequest::request::<lsp::request::ApplyWorkspaceEdit>(0, params);
+            assert_eq!(rx.next().await, Some(Outgoing::Request(request)));
+            let params = lsp::CancelParams { id: lsp::NumberOrString::Number(0) };
+            let cancel = ClientRequest::notification::<lsp::notification::Cancel>(params);
+            assert_eq!(rx.next().await, Some(Outgoing::Request(cancel)));
+        }
+
+        #[tokio::test]
+        async fn default_timeout() {
+            let (client, _rx) = helper::client(true);
+            client.set_default_timeout(Some(Duration::from_millis(10)));
+
+            let token = CancellationToken::default();
+            let result = client.send_custom_request::<lsp::request::Shutdown>((), token).await;
+            let method = <lsp::request::Shutdown as lsp::request::Request>::METHOD;
+            let timeout = Duration::from_millis(10);
+            assert_eq!(result, Err(ClientError::TimedOut { method, timeout }));
+            assert!(client.inner.pending_requests.0.is_empty());
+        }
+
+        #[tokio::test]
+        async fn show_document() {
+            let capabilities = json!({ "window": { "showDocument": { "support": true } } });
+            let (client, mut rx) = helper::client_with_capabilities(capabilities);
+            let params = lsp::ShowDocumentParams {
+                uri: lsp::Url::parse("inmemory::///test").unwrap(),
+                external: None,
+                take_focus: Some(true),
+                selection: None,
+            };
+
+            let req = client.show_document(params.clone());
+            let rsp = async {
+                let item = rx.next().await.unwrap();
+                let request = ClientRequest::request::<lsp::request::ShowDocument>(0, params.clone());
+                assert_eq!(item, Outgoing::Request(request));
+                client
+                    .inner
+                    .pending_requests
+                    .insert(Response::ok(Id::Number(0), json!({ "success": true })));
+            };
+            let (result, ()) = futures::future::join(req, rsp).await;
+            assert_eq!(result, Ok(true));
         }
 
         #[tokio::test]
@@ -815,7 +1525,8 @@ mod tests {
 
         #[tokio::test]
         async fn workspace_folders() -> anyhow::Result<()> {
-            let (client, _rx) = helper::client(true);
+            let capabilities = json!({ "workspace": { "workspaceFolders": true } });
+            let (client, _rx) = helper::client_with_capabilities(capabilities);
 
             let req = client.workspace_folders();
             let rsp = async {
@@ -836,13 +1547,13 @@ mod tests {
         #[test]
         fn debug() {
             let canceller = TokenCanceller::new();
-            format!("{:?}", canceller);
+            let _ = format!("{:?}", canceller);
         }
 
         #[test]
         fn default() {
             let canceller = TokenCanceller::default();
-            format!("{:?}", canceller);
+            let _ = format!("{:?}", canceller);
         }
     }
 }
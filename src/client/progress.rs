@@ -0,0 +1,91 @@
+//! Handle for reporting work done progress to the client.
+
+use super::Client;
+use serde::{Deserialize, Serialize};
+
+/// Handle for reporting work done progress on a single token.
+///
+/// Created with [`Client::progress`] or [`Client::attach_progress`]. If the client does not
+/// support work done progress, all methods on this handle are no-ops, so callers never need to
+/// check for support themselves.
+///
+/// [`Client::progress`]: super::Client::progress
+/// [`Client::attach_progress`]: super::Client::attach_progress
+#[derive(Clone, Debug)]
+#[must_use = "dropping a `Progress` without calling `finish` leaves the progress indicator open"]
+pub struct Progress {
+    client: Client,
+    token: Option<lsp::ProgressToken>,
+}
+
+impl Progress {
+    pub(super) fn noop(client: Client) -> Self {
+        Progress { client, token: None }
+    }
+
+    pub(super) async fn begin(client: Client, token: lsp::ProgressToken, title: String) -> Self {
+        let progress = Progress {
+            client,
+            token: Some(token),
+        };
+        let begin = lsp::WorkDoneProgressBegin {
+            title,
+            ..Default::default()
+        };
+        progress.send(lsp::WorkDoneProgress::Begin(begin)).await;
+        progress
+    }
+
+    /// Returns the progress token, or `None` if this handle does nothing.
+    pub fn token(&self) -> Option<&lsp::ProgressToken> {
+        self.token.as_ref()
+    }
+
+    /// Reports an intermediate progress update to the client.
+    ///
+    /// The `percentage` should range from 0 to 100. Clients are free to ignore it.
+    pub async fn report(&self, percentage: Option<u32>, message: Option<String>) {
+        let report = lsp::WorkDoneProgressReport {
+            cancellable: None,
+            message,
+            percentage,
+        };
+        self.send(lsp::WorkDoneProgress::Report(report)).await;
+    }
+
+    /// Signals to the client that the work is done, consuming the handle.
+    pub async fn finish(self, message: Option<String>) {
+        let end = lsp::WorkDoneProgressEnd { message };
+        self.send(lsp::WorkDoneProgress::End(end)).await;
+    }
+
+    async fn send(&self, value: lsp::WorkDoneProgress) {
+        if let Some(token) = self.token.clone() {
+            let params = lsp::ProgressParams {
+                token,
+                value: lsp::ProgressParamsValue::WorkDone(value),
+            };
+            self.client
+                .send_notification_initialized::<lsp::notification::Progress>(params)
+                .await;
+        }
+    }
+}
+
+/// Parameters of a `$/progress` notification carrying a batch of partial results.
+///
+/// `lsp::ProgressParamsValue` only models work done progress, so partial results are sent with
+/// an arbitrary JSON value instead.
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct PartialResultParams {
+    pub(super) token: lsp::ProgressToken,
+    pub(super) value: serde_json::Value,
+}
+
+pub(super) enum PartialResult {}
+
+impl lsp::notification::Notification for PartialResult {
+    type Params = PartialResultParams;
+
+    const METHOD: &'static str = "$/progress";
+}
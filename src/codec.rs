@@ -26,6 +26,10 @@ pub enum ParseError {
     /// The length value in the `Content-Length` header is invalid.
     #[error("invalid content length value")]
     InvalidLength,
+    /// Message exceeds the maximum size accepted by the codec, or its headers exceed 8 KiB. The
+    /// rest of the message is skipped.
+    #[error("message exceeds the maximum message size")]
+    MessageTooLarge,
     /// Request lacks the required `Content-Length` header.
     #[error("missing required `Content-Length` header")]
     MissingHeader,
@@ -52,9 +56,31 @@ impl From<std::str::Utf8Error> for ParseError {
     }
 }
 
+/// Maximum size of the headers of a message, so that a peer which never terminates them cannot
+/// make the codec buffer an unbounded amount of input.
+const MAX_HEADERS_SIZE: usize = 8 * 1024;
+
+/// How messages are delimited on the wire.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Framing {
+    /// Each message is preceded by a `Content-Length` header, as required by the specification.
+    #[default]
+    ContentLength,
+    /// Each message is a single line of JSON terminated by `\n`. Empty lines are ignored.
+    ///
+    /// This is not part of the specification, but is commonly used by tooling and test harnesses.
+    LineDelimited,
+}
+
 /// Encodes and decodes Language Server Protocol messages.
 #[derive(Clone, Debug)]
 pub struct LanguageServerCodec<T> {
+    framing: Framing,
+    max_message_size: Option<usize>,
+    /// Number of bytes of an oversized message which still have to be skipped.
+    skip_len: usize,
+    /// Whether an oversized line is being skipped up to its terminator.
+    skip_line: bool,
     http_error: Option<httparse::Error>,
     headers_len: Option<usize>,
     content_len: Option<usize>,
@@ -62,6 +88,24 @@ pub struct LanguageServerCodec<T> {
 }
 
 impl<T> LanguageServerCodec<T> {
+    /// Creates a codec which uses the given framing.
+    pub fn new(framing: Framing) -> Self {
+        LanguageServerCodec {
+            framing,
+            ..LanguageServerCodec::default()
+        }
+    }
+
+    /// Rejects incoming messages whose body is larger than `limit` bytes. Unlimited by default.
+    ///
+    /// The body of an oversized message is discarded as it arrives instead of being buffered, and
+    /// decoding fails with [`ParseError::MessageTooLarge`]. Decoding then resumes with the next
+    /// message.
+    pub fn max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
     fn reset(&mut self) {
         self.http_error = None;
         self.headers_len = None;
@@ -72,6 +116,10 @@ impl<T> LanguageServerCodec<T> {
 impl<T> Default for LanguageServerCodec<T> {
     fn default() -> Self {
         LanguageServerCodec {
+            framing: Framing::default(),
+            max_message_size: None,
+            skip_len: 0,
+            skip_line: false,
             http_error: None,
             headers_len: None,
             content_len: None,
@@ -80,23 +128,39 @@ impl<T> Default for LanguageServerCodec<T> {
     }
 }
 
+impl<T: serde::Serialize> LanguageServerCodec<T> {
+    fn encode_message(&mut self, item: &T, dst: &mut BytesMut) -> Result<(), ParseError> {
+        let msg = serde_json::to_string(item)?;
+        log::trace!("-> {}", msg);
+
+        match self.framing {
+            Framing::ContentLength => {
+                // Reserve just enough space to hold the `Content-Length: ` and `\r\n\r\n`
+                // constants, the length of the message, and the message body.
+                dst.reserve(msg.len() + number_of_digits(msg.len()) + 20);
+                let mut writer = dst.writer();
+                write!(writer, "Content-Length: {}\r\n\r\n{}", msg.len(), msg)?;
+                writer.flush()?;
+            },
+            Framing::LineDelimited => {
+                // Serialized JSON never contains a raw newline, since it is escaped in strings.
+                dst.reserve(msg.len() + 1);
+                dst.put_slice(msg.as_bytes());
+                dst.put_u8(b'\n');
+            },
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(feature = "runtime-agnostic")]
 impl<T: serde::Serialize> Encoder for LanguageServerCodec<T> {
     type Error = ParseError;
     type Item = T;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = serde_json::to_string(&item)?;
-        log::trace!("-> {}", msg);
-
-        // Reserve just enough space to hold the `Content-Length: ` and `\r\n\r\n` constants,
-        // the length of the message, and the message body.
-        dst.reserve(msg.len() + number_of_digits(msg.len()) + 20);
-        let mut writer = dst.writer();
-        write!(writer, "Content-Length: {}\r\n\r\n{}", msg.len(), msg)?;
-        writer.flush()?;
-
-        Ok(())
+        self.encode_message(&item, dst)
     }
 }
 
@@ -105,17 +169,7 @@ impl<T: serde::Serialize> Encoder<T> for LanguageServerCodec<T> {
     type Error = ParseError;
 
     fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = serde_json::to_string(&item)?;
-        log::trace!("-> {}", msg);
-
-        // Reserve just enough space to hold the `Content-Length: ` and `\r\n\r\n` constants,
-        // the length of the message, and the message body.
-        dst.reserve(msg.len() + number_of_digits(msg.len()) + 20);
-        let mut writer = dst.writer();
-        write!(writer, "Content-Length: {}\r\n\r\n{}", msg.len(), msg)?;
-        writer.flush()?;
-
-        Ok(())
+        self.encode_message(&item, dst)
     }
 }
 
@@ -136,6 +190,20 @@ impl<T: serde::de::DeserializeOwned> Decoder for LanguageServerCodec<T> {
     type Item = T;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.framing == Framing::LineDelimited {
+            return self.decode_line(src);
+        }
+
+        // Discard the rest of an oversized message before looking for the next one
+        if self.skip_len > 0 {
+            let len = self.skip_len.min(src.len());
+            src.advance(len);
+            self.skip_len -= len;
+            if self.skip_len > 0 {
+                return Ok(None);
+            }
+        }
+
         // Parse the headers first if necessary
         if self.headers_len.is_none() {
             {
@@ -159,6 +227,16 @@ impl<T: serde::de::DeserializeOwned> Decoder for LanguageServerCodec<T> {
                         }
                     },
                     // No errors occurred during parsing yet but no complete set of headers were parsed
+                    Ok(httparse::Status::Partial) if src.len() > MAX_HEADERS_SIZE => {
+                        // Resynchronize on the next "Content-Length", which may be cut off at the end
+                        let pattern = b"Content-Length";
+                        let offset = match twoway::find_bytes(&src[1 ..], pattern) {
+                            Some(offset) => offset + 1,
+                            None => src.len() - (pattern.len() - 1),
+                        };
+                        src.advance(offset);
+                        return Err(ParseError::MessageTooLarge);
+                    },
                     Ok(httparse::Status::Partial) => return Ok(None),
                     // An error occurred during parsing of the headers
                     Err(error) => {
@@ -170,6 +248,16 @@ impl<T: serde::de::DeserializeOwned> Decoder for LanguageServerCodec<T> {
 
         // "Content-Length" has been parsed
         if let (Some(headers_len), Some(content_len)) = (self.headers_len, self.content_len) {
+            // Skip the body of an oversized message as it arrives, rather than buffering it
+            if self.max_message_size.is_some_and(|max| content_len > max) {
+                self.reset();
+                src.advance(headers_len);
+                let len = content_len.min(src.len());
+                src.advance(len);
+                self.skip_len = content_len - len;
+                return Err(ParseError::MessageTooLarge);
+            }
+
             let delta = headers_len + content_len;
 
             // Source doesn't contain the full content yet so return and wait for more input
@@ -220,6 +308,54 @@ impl<T: serde::de::DeserializeOwned> Decoder for LanguageServerCodec<T> {
     }
 }
 
+impl<T: serde::de::DeserializeOwned> LanguageServerCodec<T> {
+    /// Decodes the next non-empty line of `src` as a JSON-RPC message.
+    fn decode_line(&mut self, src: &mut BytesMut) -> Result<Option<T>, ParseError> {
+        loop {
+            let newline = src.iter().position(|&b| b == b'\n');
+
+            // Discard the rest of an oversized line before looking for the next one
+            if self.skip_line {
+                match newline {
+                    Some(end) => {
+                        src.advance(end + 1);
+                        self.skip_line = false;
+                        continue;
+                    },
+                    None => {
+                        src.clear();
+                        return Ok(None);
+                    },
+                }
+            }
+
+            let too_large = |len: usize| self.max_message_size.is_some_and(|max| len > max);
+            let end = match newline {
+                Some(end) if too_large(end) => {
+                    src.advance(end + 1);
+                    return Err(ParseError::MessageTooLarge);
+                },
+                Some(end) => end,
+                None if too_large(src.len()) => {
+                    src.clear();
+                    self.skip_line = true;
+                    return Err(ParseError::MessageTooLarge);
+                },
+                None => return Ok(None),
+            };
+
+            let line = src.split_to(end + 1);
+            let message = std::str::from_utf8(&line)?.trim();
+            if message.is_empty() {
+                continue;
+            }
+
+            log::trace!("<- {}", message);
+            return Ok(Some(serde_json::from_str(message)?));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use bytes::BytesMut;
@@ -304,11 +440,107 @@ mod tests {
         assert_eq!(message, Some(decoded));
     }
 
+    #[test]
+    fn encode_and_decode_line_delimited() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
+        let encoded = format!("{}\n", decoded);
+
+        let mut codec = LanguageServerCodec::new(Framing::LineDelimited);
+        let mut buffer = BytesMut::new();
+        let item: Value = serde_json::from_str(&decoded).unwrap();
+        codec.encode(item, &mut buffer).unwrap();
+        assert_eq!(buffer, BytesMut::from(encoded.as_str()));
+
+        let mut buffer = BytesMut::from(format!("\r\n{}\r\n{}", decoded, &decoded[.. 10]).as_str());
+        let message = codec.decode(&mut buffer).unwrap();
+        let decoded: Value = serde_json::from_str(&decoded).unwrap();
+        assert_eq!(message, Some(decoded));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        assert_eq!(buffer.len(), 10);
+    }
+
+    #[test]
+    fn recovers_from_invalid_line() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let mut codec = LanguageServerCodec::new(Framing::LineDelimited);
+        let mut buffer = BytesMut::from(format!("{{\n{}\n", decoded).as_str());
+
+        assert!(matches!(codec.decode(&mut buffer), Err(ParseError::Body(_))));
+
+        let message = codec.decode(&mut buffer).unwrap();
+        let decoded: Value = serde_json::from_str(decoded).unwrap();
+        assert_eq!(message, Some(decoded));
+    }
+
+    #[test]
+    fn skips_oversized_message() {
+        let params = "data".repeat(100);
+        let oversized = format!(r#"{{"jsonrpc":"2.0","method":"foo","params":"{}"}}"#, params);
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let encoded = format!(
+            "Content-Length: {}\r\n\r\n{}Content-Length: {}\r\n\r\n{}",
+            oversized.len(),
+            oversized,
+            decoded.len(),
+            decoded
+        );
+
+        let mut codec = LanguageServerCodec::default().max_message_size(64);
+        let mut buffer = BytesMut::from(&encoded[.. 100]);
+        assert!(matches!(codec.decode(&mut buffer), Err(ParseError::MessageTooLarge)));
+        assert!(buffer.is_empty());
+
+        buffer.extend_from_slice(&encoded.as_bytes()[100 ..]);
+        let message = codec.decode(&mut buffer).unwrap();
+        let decoded: Value = serde_json::from_str(decoded).unwrap();
+        assert_eq!(message, Some(decoded));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn skips_oversized_headers() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let headers = format!("Content-Length: {}\r\nX-Padding: {}", decoded.len(), "a".repeat(MAX_HEADERS_SIZE));
+
+        let mut codec = LanguageServerCodec::default();
+        let mut buffer = BytesMut::from(headers.as_str());
+        assert!(matches!(codec.decode(&mut buffer), Err(ParseError::MessageTooLarge)));
+        assert!(buffer.len() < "Content-Length".len());
+
+        let encoded = format!("\r\n\r\nContent-Length: {}\r\n\r\n{}", decoded.len(), decoded);
+        buffer.extend_from_slice(encoded.as_bytes());
+        // The rest of the skipped headers fails to decode before the next message is found
+        assert!(codec.decode(&mut buffer).is_err());
+        let message = codec.decode(&mut buffer).unwrap();
+        let decoded: Value = serde_json::from_str(decoded).unwrap();
+        assert_eq!(message, Some(decoded));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn skips_oversized_line() {
+        let params = "data".repeat(100);
+        let oversized = format!(r#"{{"jsonrpc":"2.0","method":"foo","params":"{}"}}"#, params);
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let encoded = format!("{}\n{}\n{}\n", oversized, decoded, oversized);
+
+        let mut codec = LanguageServerCodec::new(Framing::LineDelimited).max_message_size(64);
+        let mut buffer = BytesMut::from(&encoded[.. 100]);
+        assert!(matches!(codec.decode(&mut buffer), Err(ParseError::MessageTooLarge)));
+        assert!(buffer.is_empty());
+
+        buffer.extend_from_slice(&encoded.as_bytes()[100 ..]);
+        let message = codec.decode(&mut buffer).unwrap();
+        let decoded: Value = serde_json::from_str(decoded).unwrap();
+        assert_eq!(message, Some(decoded));
+        assert!(matches!(codec.decode(&mut buffer), Err(ParseError::MessageTooLarge)));
+        assert!(buffer.is_empty());
+    }
+
     #[test]
     fn parse_error_from_io_error() {
-        let kind = std::io::ErrorKind::Other;
         let error = "test error";
-        let error = std::io::Error::new(kind, error);
+        let error = std::io::Error::other(error);
         let _ = ParseError::from(error);
     }
 
@@ -0,0 +1,219 @@
+//! Store of the text documents opened by the client.
+//!
+//! A [`DocumentStore`] registered with [`LspServiceBuilder::document_store`] is kept in sync with
+//! the client by the `textDocument/didOpen`, `textDocument/didChange` and `textDocument/didClose`
+//! notifications. Each notification is applied to the store before the corresponding
+//! [`LanguageServer`] handler runs, so handlers always observe the latest text.
+//!
+//! [`LspServiceBuilder::document_store`]: crate::LspServiceBuilder::document_store
+//! [`LanguageServer`]: crate::LanguageServer
+
+use crate::{LineIndex, PositionEncoding};
+use dashmap::DashMap;
+use std::sync::Arc;
+
+/// Snapshot of a text document opened by the client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Document {
+    uri: lsp::Url,
+    language_id: String,
+    version: i32,
+    text: String,
+}
+
+impl Document {
+    /// Creates a new document from the item sent in a `textDocument/didOpen` notification.
+    pub fn new(item: lsp::TextDocumentItem) -> Self {
+        Document {
+            uri: item.uri,
+            language_id: item.language_id,
+            version: item.version,
+            text: item.text,
+        }
+    }
+
+    /// Returns the URI of the document.
+    pub fn uri(&self) -> &lsp::Url {
+        &self.uri
+    }
+
+    /// Returns the language identifier the client associated with the document.
+    pub fn language_id(&self) -> &str {
+        &self.language_id
+    }
+
+    /// Returns the version of the document, which increases after each change.
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    /// Returns the full text of the document.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Applies the content changes of a `textDocument/didChange` notification, in order.
+    ///
+    /// Changes without a range replace the whole text. Ranged changes are resolved against the text
+    /// left by the previous change, with `character` offsets counted in the position encoding
+    /// negotiated with the client.
+    pub fn apply_changes(
+        &mut self,
+        version: i32,
+        changes: Vec<lsp::TextDocumentContentChangeEvent>,
+        encoding: PositionEncoding,
+    ) {
+        for change in changes {
+            match change.range {
+                None => self.text = change.text,
+                Some(range) => {
+                    let range = LineIndex::with_encoding(&self.text, encoding).offset_range(range);
+                    self.text.replace_range(range, &change.text);
+                },
+            }
+        }
+        self.version = version;
+    }
+}
+
+/// Concurrent map of the documents currently opened by the client, keyed by URI.
+///
+/// This type is cheap to clone; all clones share the same documents. Keep a clone in the language
+/// server to read documents from its handlers.
+#[derive(Clone, Debug, Default)]
+pub struct DocumentStore(Arc<DashMap<lsp::Url, Arc<Document>>>);
+
+impl DocumentStore {
+    /// Creates a new, empty document store.
+    pub fn new() -> Self {
+        DocumentStore::default()
+    }
+
+    /// Returns a snapshot of the document with the given URI, if it is open.
+    ///
+    /// The snapshot is not affected by later changes to the document.
+    pub fn get(&self, uri: &lsp::Url) -> Option<Arc<Document>> {
+        self.0.get(uri).map(|entry| entry.value().clone())
+    }
+
+    /// Returns the URIs of all open documents.
+    pub fn uris(&self) -> Vec<lsp::Url> {
+        self.0.iter().map(|entry| entry.key().clone()).collect()
+    }
+
+    pub(crate) fn open(&self, params: &lsp::DidOpenTextDocumentParams) {
+        let document = Document::new(params.text_document.clone());
+        self.0.insert(document.uri.clone(), Arc::new(document));
+    }
+
+    pub(crate) fn change(&self, params: &lsp::DidChangeTextDocumentParams, encoding: PositionEncoding) {
+        let uri = &params.text_document.uri;
+        match self.0.get_mut(uri) {
+            Some(mut entry) => {
+                let document = Arc::make_mut(entry.value_mut());
+                document.apply_changes(params.text_document.version, params.content_changes.clone(), encoding);
+            },
+            None => log::warn!("received changes for {}, which is not open, ignoring", uri),
+        }
+    }
+
+    pub(crate) fn close(&self, params: &lsp::DidCloseTextDocumentParams) {
+        self.0.remove(&params.text_document.uri);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(text: &str) -> Document {
+        let uri = lsp::Url::parse("inmemory::///test").unwrap();
+        Document::new(lsp::TextDocumentItem::new(uri, "plaintext".into(), 1, text.into()))
+    }
+
+    fn change(start: (u32, u32), end: (u32, u32), text: &str) -> lsp::TextDocumentContentChangeEvent {
+        lsp::TextDocumentContentChangeEvent {
+            range: Some(lsp::Range::new(
+                lsp::Position::new(start.0, start.1),
+                lsp::Position::new(end.0, end.1),
+            )),
+            range_length: None,
+            text: text.into(),
+        }
+    }
+
+    #[test]
+    fn apply_full_change() {
+        let mut document = document("hello");
+        let change = lsp::TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: "goodbye".into(),
+        };
+        document.apply_changes(2, vec![change], PositionEncoding::Utf16);
+        assert_eq!(document.text(), "goodbye");
+        assert_eq!(document.version(), 2);
+    }
+
+    #[test]
+    fn apply_ranged_changes_in_order() {
+        let mut document = document("fn main() {\n    foo();\n}\n");
+        let changes = vec![
+            change((1, 4), (1, 7), "bar"),
+            change((1, 10), (1, 10), "\n    baz();"),
+            change((0, 0), (0, 0), "// entry\n"),
+        ];
+        document.apply_changes(2, changes, PositionEncoding::Utf16);
+        assert_eq!(document.text(), "// entry\nfn main() {\n    bar();\n    baz();\n}\n");
+    }
+
+    #[test]
+    fn apply_change_utf16() {
+        // `𐐀` is two UTF-16 code units and four UTF-8 bytes, `é` is one and two respectively.
+        let mut document = document("a𐐀é\r\nb");
+        document.apply_changes(2, vec![change((0, 3), (0, 4), "e")], PositionEncoding::Utf16);
+        assert_eq!(document.text(), "a𐐀e\r\nb");
+        document.apply_changes(3, vec![change((0, 1), (0, 3), "")], PositionEncoding::Utf16);
+        assert_eq!(document.text(), "ae\r\nb");
+    }
+
+    #[test]
+    fn apply_change_utf32() {
+        let mut document = document("a𐐀é\r\nb");
+        document.apply_changes(2, vec![change((0, 2), (0, 3), "e")], PositionEncoding::Utf32);
+        assert_eq!(document.text(), "a𐐀e\r\nb");
+    }
+
+    #[test]
+    fn apply_change_out_of_bounds() {
+        let mut document = document("ab\r\ncd");
+        document.apply_changes(2, vec![change((0, 10), (1, 0), "")], PositionEncoding::Utf16);
+        assert_eq!(document.text(), "abcd");
+        document.apply_changes(3, vec![change((5, 0), (6, 0), "!")], PositionEncoding::Utf16);
+        assert_eq!(document.text(), "abcd!");
+    }
+
+    #[test]
+    fn store_snapshots() {
+        let store = DocumentStore::new();
+        let uri = lsp::Url::parse("inmemory::///test").unwrap();
+        let item = lsp::TextDocumentItem::new(uri.clone(), "plaintext".into(), 1, "hello".into());
+        store.open(&lsp::DidOpenTextDocumentParams { text_document: item });
+
+        let snapshot = store.get(&uri).unwrap();
+        let params = lsp::DidChangeTextDocumentParams {
+            text_document: lsp::VersionedTextDocumentIdentifier::new(uri.clone(), 2),
+            content_changes: vec![change((0, 5), (0, 5), " world")],
+        };
+        store.change(&params, PositionEncoding::Utf16);
+        assert_eq!(snapshot.text(), "hello");
+        assert_eq!(store.get(&uri).unwrap().text(), "hello world");
+        assert_eq!(store.get(&uri).unwrap().version(), 2);
+        assert_eq!(store.uris(), vec![uri.clone()]);
+
+        store.close(&lsp::DidCloseTextDocumentParams {
+            text_document: lsp::TextDocumentIdentifier::new(uri.clone()),
+        });
+        assert_eq!(store.get(&uri), None);
+    }
+}
@@ -90,6 +90,14 @@ impl Response {
             ResponseKind::Err { ref id, .. } => id.as_ref(),
         }
     }
+
+    /// Returns the error code, if this is an error response.
+    pub(crate) fn error_code(&self) -> Option<ErrorCode> {
+        match self.kind {
+            ResponseKind::Ok { .. } => None,
+            ResponseKind::Err { ref error, .. } => Some(error.code),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -102,8 +110,7 @@ enum ResponseKind {
 
 #[allow(clippy::large_enum_variant)]
 /// An incoming JSON-RPC message.
-#[derive(Clone, Debug, PartialEq, Deserialize)]
-#[cfg_attr(test, derive(Serialize))]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Incoming {
     /// Request intended for the language server.
@@ -112,6 +119,39 @@ pub enum Incoming {
     Response(Response),
 }
 
+impl Incoming {
+    /// Creates a request from its method name, ID and parameters, e.g. to rewrite a request in
+    /// middleware. Without an ID, the request is a notification.
+    pub fn from_parts(method: impl Into<String>, id: Option<Id>, params: Option<Value>) -> Self {
+        let request = crate::generated_impl::ServerRequest::new(method.into(), id, params);
+        Incoming::Request(Box::new(request))
+    }
+
+    /// Returns the method name of a request, or `None` for a response.
+    pub fn method(&self) -> Option<&str> {
+        match self {
+            Incoming::Request(req) => Some(req.method()),
+            Incoming::Response(_) => None,
+        }
+    }
+
+    /// Returns the ID of a request or response, or `None` for a notification.
+    pub fn id(&self) -> Option<&Id> {
+        match self {
+            Incoming::Request(req) => req.id(),
+            Incoming::Response(res) => res.id(),
+        }
+    }
+
+    /// Returns the raw parameters of a request, if any.
+    pub fn params(&self) -> Option<&Value> {
+        match self {
+            Incoming::Request(req) => req.params(),
+            Incoming::Response(_) => None,
+        }
+    }
+}
+
 /// A server-to-client LSP request.
 #[derive(Clone, Debug, PartialEq, Serialize)]
 #[cfg_attr(test, derive(Deserialize))]
@@ -149,6 +189,14 @@ impl ClientRequest {
             },
         }
     }
+
+    /// Returns the ID of the request, or `None` if it is a notification.
+    pub(crate) fn id(&self) -> Option<&Id> {
+        match self.kind {
+            ClientMethod::Request { ref id, .. } => Some(id),
+            ClientMethod::Notification { .. } => None,
+        }
+    }
 }
 
 impl Display for ClientRequest {
@@ -193,7 +241,7 @@ impl<'a, 'b> std::io::Write for WriterFormatter<'a, 'b> {
         fn io_error<E>(_: E) -> std::io::Error {
             // Error value does not matter because fmt::Display impl below just
             // maps it to fmt::Error
-            std::io::Error::new(std::io::ErrorKind::Other, "fmt error")
+            std::io::Error::other("fmt error")
         }
         let s = std::str::from_utf8(buf).map_err(io_error)?;
         self.inner.write_str(s).map_err(io_error)?;
@@ -229,6 +277,32 @@ impl Serialize for Version {
     }
 }
 
+/// Parameters of an incoming request or notification.
+///
+/// Deserialization never fails outright; malformed or missing parameters are captured as
+/// `Invalid` so the router can answer with an "invalid params" error instead of dropping the
+/// message.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(test, derive(Serialize))]
+pub(crate) enum Params<T> {
+    Valid(T),
+    #[cfg_attr(test, serde(skip_serializing))]
+    Invalid(String),
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Params<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Deserialize::deserialize(deserializer) {
+            Ok(Some(v)) => Ok(Params::Valid(v)),
+            Ok(None) => Ok(Params::Invalid("Missing params field".to_string())),
+            Err(e) => Ok(Params::Invalid(e.to_string())),
+        }
+    }
+}
+
 /// Error response returned for every request received before the server is initialized.
 ///
 /// See [here](https://microsoft.github.io/language-server-protocol/specification#initialize)
@@ -252,7 +326,7 @@ mod tests {
         fn display() {
             let id = 0;
             let request = ClientRequest::request::<lsp::request::Shutdown>(id, ());
-            format!("{}", request);
+            let _ = format!("{}", request);
         }
     }
 
@@ -272,6 +346,41 @@ mod tests {
         }
     }
 
+    mod incoming {
+        use super::*;
+        use serde_json::json;
+
+        #[test]
+        fn request_accessors() {
+            let raw = json!({ "jsonrpc": "2.0", "method": "textDocument/hover", "params": { "a": 1 }, "id": 1 });
+            let incoming: Incoming = serde_json::from_value(raw).unwrap();
+            assert_eq!(incoming.method(), Some("textDocument/hover"));
+            assert_eq!(incoming.id(), Some(&Id::Number(1)));
+            assert_eq!(incoming.params(), Some(&json!({ "a": 1 })));
+
+            let built = Incoming::from_parts("textDocument/hover", Some(Id::Number(1)), Some(json!({ "a": 1 })));
+            assert_eq!(built, incoming);
+        }
+
+        #[test]
+        fn notification_accessors() {
+            let raw = json!({ "jsonrpc": "2.0", "method": "exit" });
+            let incoming: Incoming = serde_json::from_value(raw).unwrap();
+            assert_eq!(incoming.method(), Some("exit"));
+            assert_eq!(incoming.id(), None);
+            assert_eq!(incoming.params(), None);
+            assert_eq!(Incoming::from_parts("exit", None, None), incoming);
+        }
+
+        #[test]
+        fn response_accessors() {
+            let incoming = Incoming::Response(Response::ok(Id::Number(1), json!(null)));
+            assert_eq!(incoming.method(), None);
+            assert_eq!(incoming.id(), Some(&Id::Number(1)));
+            assert_eq!(incoming.params(), None);
+        }
+    }
+
     mod outgoing {
         use super::*;
         use serde_json::json;
@@ -175,6 +175,20 @@ impl Error {
     pub fn content_modified() -> Self {
         Error::new(ErrorCode::ContentModified)
     }
+
+    /// Creates a new "request failed" error (`-32803`) with the given message.
+    ///
+    /// # Compatibility
+    ///
+    /// This error code is defined by the Language Server Protocol since version 3.17. It has no
+    /// dedicated [`ErrorCode`] variant and is represented as `ErrorCode::ServerError(-32803)`.
+    pub fn request_failed(message: impl Into<String>) -> Self {
+        Error {
+            code: ErrorCode::ServerError(-32803),
+            message: message.into(),
+            data: None,
+        }
+    }
 }
 
 impl Display for Error {
@@ -265,6 +279,15 @@ mod tests {
         assert_eq!(code.description(), error.message);
     }
 
+    #[test]
+    fn request_failed() {
+        let code = ErrorCode::ServerError(-32803);
+        assert_eq!(code, code.code().into());
+        let error = Error::request_failed("handler timed out");
+        assert_eq!(code, error.code);
+        assert_eq!("handler timed out", error.message);
+    }
+
     #[test]
     fn server_error() {
         let code = ErrorCode::ServerError(42);
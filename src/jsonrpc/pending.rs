@@ -1,45 +1,205 @@
 //! Hashmaps for tracking pending JSON-RPC requests.
 
 use super::{Error, Id, Response, Result};
+use crate::client::{RequestScope, TokenCanceller};
 use dashmap::{mapref::entry::Entry, DashMap};
-use futures::{channel::oneshot, future};
+use futures::{channel::oneshot, future, TryFutureExt};
 use serde::Serialize;
 use std::{
+    collections::{HashMap, HashSet},
     fmt::{self, Debug, Formatter},
     future::Future,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+/// Default time given to a handler which asked for its cancellation token to return once the
+/// token is signalled, before the handler is aborted.
+const DEFAULT_CANCELLATION_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// A request handler which is still running.
+struct PendingRequest {
+    abort_handle: future::AbortHandle,
+    canceller: TokenCanceller,
+    document: Option<lsp::Url>,
+    modified: Arc<AtomicBool>,
+}
+
+/// Removes a request from the pending requests when dropped, i.e. once its handler has finished
+/// or the future driving it has been dropped.
+struct Finished(Arc<DashMap<Id, PendingRequest>>, Id);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.remove(&self.1);
+    }
+}
+
 /// A hashmap containing pending server requests, keyed by request ID.
-pub struct ServerRequests(Arc<DashMap<Id, future::AbortHandle>>);
+///
+/// Clones share the same pending requests.
+#[derive(Clone)]
+pub struct ServerRequests {
+    requests: Arc<DashMap<Id, PendingRequest>>,
+    invalidated_methods: HashSet<&'static str>,
+    timeouts: HashMap<&'static str, Duration>,
+    grace_period: Duration,
+}
 
 impl ServerRequests {
     /// Creates a new pending server requests map.
     pub fn new() -> Self {
-        ServerRequests(Arc::new(DashMap::new()))
+        ServerRequests {
+            requests: Arc::new(DashMap::new()),
+            invalidated_methods: HashSet::new(),
+            timeouts: HashMap::new(),
+            grace_period: DEFAULT_CANCELLATION_GRACE_PERIOD,
+        }
+    }
+
+    /// Sets the time after which handlers for `method` are aborted.
+    pub(crate) fn set_timeout(&mut self, method: &'static str, timeout: Duration) {
+        self.timeouts.insert(method, timeout);
+    }
+
+    /// Sets the time given to handlers which asked for their cancellation token to return once it
+    /// is signalled.
+    pub(crate) fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Marks requests for `method` as invalidated by changes to the document they refer to.
+    pub(crate) fn invalidate_on_change(&mut self, method: &'static str) {
+        self.invalidated_methods.insert(method);
+    }
+
+    /// Returns the URI of the document which `params` refer to, if requests for `method` are
+    /// invalidated by changes to it.
+    pub(crate) fn document_of<P: Serialize>(&self, method: &str, params: &P) -> Option<lsp::Url> {
+        if !self.invalidated_methods.contains(method) {
+            return None;
+        }
+        let params = serde_json::to_value(params).ok()?;
+        serde_json::from_value(params.pointer("/textDocument/uri")?.clone()).ok()
     }
 
     /// Executes the given async request handler, keyed by the given request ID.
     ///
     /// If a cancel request is issued before the future is finished resolving, this will resolve to
     /// a "canceled" error response, and the pending request handler future will be dropped.
-    pub fn execute<F, T>(&self, id: Id, fut: F) -> impl Future<Output = Response> + Send + 'static
+    ///
+    /// While the future is polled, its cancellation token is available through
+    /// [`CancellationToken::current`]. Handlers which ask for it are given a short grace period to
+    /// clean up and return once the token is signalled, in which case their own result is sent.
+    /// They are dropped like any other handler if they are still running afterwards.
+    ///
+    /// If a timeout was set for `method` and the handler is still running when it elapses, the
+    /// handler is cancelled the same way and this resolves to a "request failed" error response.
+    ///
+    /// The response is only produced after the handler future has resolved. Any notifications the
+    /// handler awaited on the `Client` while running, such as partial results streamed through
+    /// `$/progress`, are therefore already queued in the `MessageStream` by the time the response
+    /// exists. `Server::serve` writes up to 64 of the messages queued in that stream before a ready
+    /// response, so that a server which keeps sending messages cannot delay it indefinitely. The
+    /// final response follows every partial result unless more than 64 messages were queued ahead
+    /// of it.
+    ///
+    /// [`CancellationToken::current`]: crate::CancellationToken::current
+    pub fn execute<F, T>(&self, id: Id, method: &str, fut: F) -> impl Future<Output = Response> + Send + 'static
     where
         F: Future<Output = Result<T>> + Send + 'static,
         T: Serialize,
     {
-        if let Entry::Vacant(entry) = self.0.entry(id.clone()) {
-            let (handler_fut, abort_handle) = future::abortable(fut);
-            entry.insert(abort_handle);
+        self.execute_on(id, method, None, fut)
+    }
 
-            let requests = self.0.clone();
+    /// Like [`execute`](Self::execute), but remembers the document the request refers to.
+    ///
+    /// If `document` is later passed to [`invalidate`](Self::invalidate) while the handler is still
+    /// running, this resolves to a "content modified" error response instead.
+    pub(crate) fn execute_on<F, T>(
+        &self,
+        id: Id,
+        method: &str,
+        document: Option<lsp::Url>,
+        fut: F,
+    ) -> impl Future<Output = Response> + Send + 'static
+    where
+        F: Future<Output = Result<T>> + Send + 'static,
+        T: Serialize,
+    {
+        if let Entry::Vacant(entry) = self.requests.entry(id.clone()) {
+            let canceller = TokenCanceller::new();
+            let token = canceller.token();
+            let cooperative = Arc::new(AtomicBool::new(false));
+            let modified = Arc::new(AtomicBool::new(false));
+            let fut = fut.map_ok(|v| serde_json::to_value(v).unwrap());
+            let fut = RequestScope::new(token.clone(), cooperative.clone(), fut);
+            let (handler_fut, abort_handle) = future::abortable(fut);
+            entry.insert(PendingRequest {
+                abort_handle: abort_handle.clone(),
+                canceller,
+                document,
+                modified: modified.clone(),
+            });
+
+            let requests = self.requests.clone();
+            let finished = Finished(requests.clone(), id.clone());
+            let grace = self.grace_period;
+            let mut timeout = self.timeouts.get(method).map(|&timeout| (method.to_owned(), timeout));
             future::Either::Left(async move {
-                let abort_result = handler_fut.await;
-                requests.remove(&id); // Remove abort handle now to avoid double cancellation.
-
-                if let Ok(handler_result) = abort_result {
-                    let result = handler_result.map(|v| serde_json::to_value(v).unwrap());
-                    Response::from_parts(id, result)
+                let duration = timeout.as_ref().map(|&(_, timeout)| timeout);
+                let deadline = async move {
+                    match duration {
+                        Some(duration) => crate::time::sleep(duration).await,
+                        None => future::pending().await,
+                    }
+                };
+
+                let mut timed_out = None;
+                let abort_result = {
+                    // Remove abort handle once finished to avoid double cancellation, even on panic or
+                    // if this future is dropped before it is first polled.
+                    let _finished = finished;
+                    futures::pin_mut!(handler_fut, deadline);
+                    let cancelled = future::select(token.wait(), deadline);
+                    match future::select(handler_fut.as_mut(), cancelled).await {
+                        future::Either::Left((abort_result, _)) => abort_result,
+                        future::Either::Right((reason, _)) => {
+                            if let future::Either::Right(_) = reason {
+                                if let Some(mut request) = requests.get_mut(&id) {
+                                    request.canceller.cancel();
+                                }
+                                timed_out = timeout.take();
+                            }
+                            let grace_period = async {
+                                if cooperative.load(Ordering::SeqCst) {
+                                    crate::time::sleep(grace).await;
+                                }
+                            };
+                            futures::pin_mut!(grace_period);
+                            match future::select(handler_fut.as_mut(), grace_period).await {
+                                future::Either::Left((abort_result, _)) => abort_result,
+                                future::Either::Right(_) => {
+                                    abort_handle.abort();
+                                    handler_fut.await
+                                },
+                            }
+                        },
+                    }
+                };
+
+                if let Some((method, timeout)) = timed_out {
+                    log::warn!("request {} for {} timed out after {:?}, aborted", id, method, timeout);
+                    let message = format!("Request {:?} timed out after {:?}", method, timeout);
+                    Response::error(Some(id), Error::request_failed(message))
+                } else if modified.load(Ordering::SeqCst) {
+                    Response::error(Some(id), Error::content_modified())
+                } else if let Ok(handler_result) = abort_result {
+                    Response::from_parts(id, handler_result)
                 } else {
                     Response::error(Some(id), Error::request_cancelled())
                 }
@@ -51,11 +211,14 @@ impl ServerRequests {
 
     /// Attempts to cancel the running request handler corresponding to this ID.
     ///
-    /// This will force the future to resolve to a "canceled" error response. If the future has
-    /// already completed, this method call will do nothing.
+    /// This signals the request's cancellation token and forces the future to resolve to a
+    /// "canceled" error response, after a grace period if the handler asked for its token. If the
+    /// future has already completed, this method call will do nothing.
+    ///
+    /// The request stays pending until its handler has stopped, so its ID cannot be reused before.
     pub fn cancel(&self, id: &Id) {
-        if let Some((_, handle)) = self.0.remove(id) {
-            handle.abort();
+        if let Some(mut request) = self.requests.get_mut(id) {
+            request.canceller.cancel();
             log::info!("successfully cancelled request with ID: {}", id);
         } else {
             log::warn!(
@@ -65,10 +228,34 @@ impl ServerRequests {
         }
     }
 
+    /// Cancels all pending requests which refer to the given document, answering them with a
+    /// "content modified" error response.
+    ///
+    /// Like [`cancel`](Self::cancel), this leaves handlers which asked for their cancellation token
+    /// a grace period to return.
+    pub(crate) fn invalidate(&self, document: &lsp::Url) {
+        for mut request in self.requests.iter_mut() {
+            if request.document.as_ref() == Some(document) {
+                log::info!("request {} invalidated by a change to {}", request.key(), document);
+                request.modified.store(true, Ordering::SeqCst);
+                request.canceller.cancel();
+            }
+        }
+    }
+
+    /// Returns the number of request handlers which are currently running.
+    pub(crate) fn len(&self) -> usize {
+        self.requests.len()
+    }
+
     /// Cancels all pending request handlers, if any.
+    ///
+    /// Unlike [`cancel`](Self::cancel), this always aborts the handlers, since no response can be
+    /// delivered anymore.
     pub fn cancel_all(&self) {
-        self.0.retain(|_, handle| {
-            handle.abort();
+        self.requests.retain(|_, request| {
+            request.canceller.cancel();
+            request.abort_handle.abort();
             false
         });
     }
@@ -77,7 +264,7 @@ impl ServerRequests {
 impl Debug for ServerRequests {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         f.debug_set()
-            .entries(self.0.iter().map(|entry| entry.key().clone()))
+            .entries(self.requests.iter().map(|entry| entry.key().clone()))
             .finish()
     }
 }
@@ -91,6 +278,11 @@ impl ClientRequests {
         ClientRequests(DashMap::new())
     }
 
+    /// Returns the number of requests which are still waiting for a response.
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
     /// Inserts the given response into the map.
     ///
     /// The corresponding `.wait()` future will then resolve to the given value.
@@ -143,7 +335,7 @@ mod tests {
         #[test]
         fn debug() {
             let client_requests = ClientRequests::new();
-            format!("{:?}", client_requests);
+            let _ = format!("{:?}", client_requests);
         }
 
         #[tokio::test]
@@ -186,7 +378,7 @@ mod tests {
         #[test]
         fn debug() {
             let server_requests = ServerRequests::new();
-            format!("{:?}", server_requests);
+            let _ = format!("{:?}", server_requests);
         }
 
         #[tokio::test]
@@ -194,7 +386,7 @@ mod tests {
             let pending = ServerRequests::new();
 
             let id = Id::Number(1);
-            let response = pending.execute(id.clone(), async { Ok(json!({})) }).await;
+            let response = pending.execute(id.clone(), "foo", async { Ok(json!({})) }).await;
 
             assert_eq!(response, Response::ok(id, json!({})));
         }
@@ -203,8 +395,8 @@ mod tests {
         async fn execute_concurrent() {
             let pending = ServerRequests::new();
             let id = Id::Number(1);
-            let fut0 = pending.execute(id.clone(), async { Ok(json!({})) });
-            let fut1 = pending.execute(id.clone(), async { Ok(json!({})) });
+            let fut0 = pending.execute(id.clone(), "foo", async { Ok(json!({})) });
+            let fut1 = pending.execute(id.clone(), "foo", async { Ok(json!({})) });
             assert_eq!(fut0.await, Response::ok(id.clone(), json!({})));
             assert_eq!(fut1.await, Response::error(Some(id.clone()), Error::invalid_request()));
         }
@@ -214,7 +406,7 @@ mod tests {
             let pending = ServerRequests::new();
 
             let id = Id::Number(1);
-            let handler_fut = tokio::spawn(pending.execute(id.clone(), async {
+            let handler_fut = tokio::spawn(pending.execute(id.clone(), "foo", async {
                 tokio::time::sleep(Duration::from_secs(50)).await;
                 Ok(json!({}))
             }));
@@ -226,6 +418,85 @@ mod tests {
             assert_eq!(res, Response::error(Some(id), Error::request_cancelled()));
         }
 
+        #[tokio::test]
+        async fn cancel_cooperative() {
+            let pending = ServerRequests::new();
+            assert!(crate::CancellationToken::current().is_none());
+
+            let id = Id::Number(1);
+            let handler_fut = tokio::spawn(pending.execute(id.clone(), "foo", async {
+                let token = crate::CancellationToken::current().expect("no token in request handler");
+                let blocking = tokio::task::spawn_blocking(move || {
+                    while !token.is_cancelled() {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                });
+                blocking.await.unwrap();
+                Err::<(), _>(Error::request_cancelled())
+            }));
+
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            pending.cancel(&id);
+
+            let res = handler_fut.await.expect("task panicked");
+            assert_eq!(res, Response::error(Some(id), Error::request_cancelled()));
+        }
+
+        #[tokio::test]
+        async fn cancel_cooperative_completes() {
+            let pending = ServerRequests::new();
+
+            let id = Id::Number(1);
+            let handler_fut = tokio::spawn(pending.execute(id.clone(), "foo", async {
+                let token = crate::CancellationToken::current().unwrap();
+                token.wait().await.unwrap();
+                Ok(json!("cleaned up"))
+            }));
+
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            pending.cancel(&id);
+
+            let res = handler_fut.await.expect("task panicked");
+            assert_eq!(res, Response::ok(id, json!("cleaned up")));
+        }
+
+        #[tokio::test]
+        async fn cancel_cooperative_aborts_after_grace_period() {
+            let grace_period = Duration::from_millis(100);
+            let mut pending = ServerRequests::new();
+            pending.set_grace_period(grace_period);
+
+            let id = Id::Number(1);
+            let handler_fut = tokio::spawn(pending.execute(id.clone(), "foo", async {
+                let _token = crate::CancellationToken::current().unwrap();
+                tokio::time::sleep(Duration::from_secs(50)).await;
+                Ok(json!({}))
+            }));
+
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            pending.cancel(&id);
+
+            // The handler is still running, so its ID cannot be reused yet.
+            let reused = pending.execute(id.clone(), "foo", async { Ok(json!({})) }).await;
+            assert_eq!(reused, Response::error(Some(id.clone()), Error::invalid_request()));
+
+            let res = tokio::time::timeout(2 * grace_period, handler_fut).await;
+            let res = res.expect("handler was not aborted").expect("task panicked");
+            assert_eq!(res, Response::error(Some(id), Error::request_cancelled()));
+            assert!(pending.requests.is_empty());
+        }
+
+        #[tokio::test]
+        async fn execute_dropped_before_polled() {
+            let pending = ServerRequests::new();
+            let id = Id::Number(1);
+            drop(pending.execute(id.clone(), "foo", async { Ok(json!({})) }));
+            assert!(pending.requests.is_empty());
+
+            let res = pending.execute(id.clone(), "foo", async { Ok(json!({})) }).await;
+            assert_eq!(res, Response::ok(id, json!({})));
+        }
+
         #[tokio::test]
         async fn cancel_non_existent() {
             let pending = ServerRequests::new();
@@ -233,18 +504,94 @@ mod tests {
             pending.cancel(&id);
         }
 
+        #[tokio::test]
+        async fn invalidate() {
+            let mut pending = ServerRequests::new();
+            pending.invalidate_on_change("textDocument/codeLens");
+
+            let changed = lsp::Url::parse("inmemory::///changed").unwrap();
+            let other = lsp::Url::parse("inmemory::///other").unwrap();
+            let params = |uri: &lsp::Url| json!({ "textDocument": { "uri": uri } });
+            assert_eq!(pending.document_of("textDocument/codeLens", &params(&changed)), Some(changed.clone()));
+            assert_eq!(pending.document_of("textDocument/hover", &params(&changed)), None);
+
+            let id1 = Id::Number(1);
+            let handler_fut1 = tokio::spawn(pending.execute_on(id1.clone(), "foo", Some(changed.clone()), async {
+                tokio::time::sleep(Duration::from_secs(50)).await;
+                Ok(json!({}))
+            }));
+
+            let id2 = Id::Number(2);
+            let handler_fut2 = tokio::spawn(pending.execute_on(id2.clone(), "foo", Some(other), async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok(json!({}))
+            }));
+
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            pending.invalidate(&changed);
+
+            let res1 = handler_fut1.await.expect("task panicked");
+            assert_eq!(res1, Response::error(Some(id1), Error::content_modified()));
+
+            let res2 = handler_fut2.await.expect("task panicked");
+            assert_eq!(res2, Response::ok(id2, json!({})));
+        }
+
+        #[tokio::test]
+        async fn execute_with_timeout() {
+            let mut pending = ServerRequests::new();
+            pending.set_timeout("slow", Duration::from_millis(30));
+
+            let id1 = Id::Number(1);
+            let handler_fut1 = pending.execute(id1.clone(), "slow", async {
+                tokio::time::sleep(Duration::from_secs(50)).await;
+                Ok(json!({}))
+            });
+
+            let id2 = Id::Number(2);
+            let handler_fut2 = pending.execute(id2.clone(), "other", async {
+                tokio::time::sleep(Duration::from_millis(60)).await;
+                Ok(json!({}))
+            });
+
+            let (res1, res2) = futures::join!(handler_fut1, handler_fut2);
+            let error = Error::request_failed("Request \"slow\" timed out after 30ms");
+            assert_eq!(res1, Response::error(Some(id1), error));
+            assert_eq!(res2, Response::ok(id2, json!({})));
+            assert!(pending.requests.is_empty());
+        }
+
+        #[tokio::test]
+        async fn execute_with_timeout_cooperative() {
+            let mut pending = ServerRequests::new();
+            pending.set_timeout("slow", Duration::from_millis(30));
+
+            let (sender, receiver) = oneshot::channel();
+            let id = Id::Number(1);
+            let response = pending.execute(id.clone(), "slow", async {
+                let token = crate::CancellationToken::current().unwrap();
+                token.wait().await.unwrap();
+                sender.send(()).unwrap();
+                Ok(json!({}))
+            });
+
+            let error = Error::request_failed("Request \"slow\" timed out after 30ms");
+            assert_eq!(response.await, Response::error(Some(id), error));
+            assert_eq!(receiver.await, Ok(()));
+        }
+
         #[tokio::test]
         async fn cancel_all() {
             let pending = ServerRequests::new();
 
             let id1 = Id::Number(1);
-            let handler_fut1 = tokio::spawn(pending.execute(id1.clone(), async {
+            let handler_fut1 = tokio::spawn(pending.execute(id1.clone(), "foo", async {
                 tokio::time::sleep(Duration::from_secs(50)).await;
                 Ok(json!({}))
             }));
 
             let id2 = Id::Number(2);
-            let handler_fut2 = tokio::spawn(pending.execute(id2.clone(), async {
+            let handler_fut2 = tokio::spawn(pending.execute(id2.clone(), "foo", async {
                 tokio::time::sleep(Duration::from_secs(50)).await;
                 Ok(json!({}))
             }));
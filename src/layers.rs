@@ -0,0 +1,369 @@
+//! [`tower`] middleware for [`LspService`].
+//!
+//! Each layer wraps any service which handles [`Incoming`] messages, so they can be stacked on top
+//! of an [`LspService`] before it is passed to [`Server::serve`]:
+//!
+//! ```rust,ignore
+//! use lspower::layers::{CatchPanicLayer, LatencyLayer, Layer, LogLayer};
+//!
+//! let (service, messages) = LspService::new(|client| Backend { client });
+//! let latency = LatencyLayer::new(|method: &str, elapsed| log::info!("{}: {:?}", method, elapsed));
+//! let service = LogLayer.layer(latency.layer(CatchPanicLayer.layer(service)));
+//! Server::new(stdin, stdout).interleave(messages).serve(service).await
+//! ```
+//!
+//! Responses sent by the client to server-to-client requests pass through the latency and logging
+//! layers untouched, since they are not handled by a method.
+//!
+//! [`tower`]: https://github.com/tower-rs/tower
+//! [`LspService`]: crate::LspService
+//! [`Server::serve`]: crate::Server::serve
+
+use crate::jsonrpc::{Error, Id, Incoming, Outgoing, Response};
+use futures::{future, FutureExt};
+use std::{
+    any::Any,
+    fmt::{self, Debug, Formatter},
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower_service::Service;
+
+pub use tower_layer::Layer;
+
+type BoxFuture<T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send>>;
+
+/// Returns the method name and ID of a request, or `None` for a response sent by the client.
+fn describe(msg: &Incoming) -> Option<(String, Option<Id>)> {
+    let method = msg.method()?;
+    Some((method.to_owned(), msg.id().cloned()))
+}
+
+/// Layer which turns panics in request handlers into `InternalError` responses.
+///
+/// Without it, a panicking handler unwinds through [`Server::serve`] and brings down the server.
+/// Panics while handling notifications are logged and otherwise ignored.
+///
+/// [`Server::serve`]: crate::Server::serve
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CatchPanicLayer;
+
+impl<S> Layer<S> for CatchPanicLayer {
+    type Service = CatchPanic<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CatchPanic { inner }
+    }
+}
+
+/// Service created by [`CatchPanicLayer`].
+#[derive(Clone, Debug)]
+pub struct CatchPanic<S> {
+    inner: S,
+}
+
+impl<S> CatchPanic<S> {
+    fn recover(request: Option<(String, Option<Id>)>, panic: Box<dyn Any + Send>) -> Option<Outgoing> {
+        let reason = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        let (method, id) = request.unwrap_or_else(|| ("client response".into(), None));
+        log::error!("handler for {} panicked: {}", method, reason);
+        id.map(|id| Outgoing::Response(Response::error(Some(id), Error::internal_error())))
+    }
+}
+
+impl<S> Service<Incoming> for CatchPanic<S>
+where
+    S: Service<Incoming, Response = Option<Outgoing>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+    type Response = Option<Outgoing>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, msg: Incoming) -> Self::Future {
+        let request = describe(&msg);
+        let inner = &mut self.inner;
+        match panic::catch_unwind(AssertUnwindSafe(|| inner.call(msg))) {
+            Ok(fut) => Box::pin(AssertUnwindSafe(fut).catch_unwind().map(|result| match result {
+                Ok(response) => response,
+                Err(panic) => Ok(Self::recover(request, panic)),
+            })),
+            Err(panic) => future::ok(Self::recover(request, panic)).boxed(),
+        }
+    }
+}
+
+/// Layer which reports how long each message took to handle, keyed by method name.
+///
+/// The callback receives the method name and the time from the call until the response or
+/// notification handler resolved.
+#[derive(Clone)]
+pub struct LatencyLayer<F> {
+    record: Arc<F>,
+}
+
+impl<F> LatencyLayer<F>
+where
+    F: Fn(&str, Duration) + Send + Sync + 'static,
+{
+    /// Creates a layer which passes the latency of every handled message to `record`.
+    pub fn new(record: F) -> Self {
+        LatencyLayer {
+            record: Arc::new(record),
+        }
+    }
+}
+
+impl<F> Debug for LatencyLayer<F> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(LatencyLayer)).finish_non_exhaustive()
+    }
+}
+
+impl<S, F> Layer<S> for LatencyLayer<F> {
+    type Service = Latency<S, F>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Latency {
+            inner,
+            record: self.record.clone(),
+        }
+    }
+}
+
+/// Service created by [`LatencyLayer`].
+#[derive(Clone)]
+pub struct Latency<S, F> {
+    inner: S,
+    record: Arc<F>,
+}
+
+impl<S: Debug, F> Debug for Latency<S, F> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(Latency))
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, F> Service<Incoming> for Latency<S, F>
+where
+    S: Service<Incoming, Response = Option<Outgoing>>,
+    S::Future: Send + 'static,
+    F: Fn(&str, Duration) + Send + Sync + 'static,
+{
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+    type Response = Option<Outgoing>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, msg: Incoming) -> Self::Future {
+        let method = match describe(&msg) {
+            Some((method, _)) => method,
+            None => return self.inner.call(msg).boxed(),
+        };
+
+        let record = self.record.clone();
+        let start = Instant::now();
+        self.inner
+            .call(msg)
+            .inspect(move |_| record(&method, start.elapsed()))
+            .boxed()
+    }
+}
+
+/// Target of the records logged by [`LogLayer`].
+pub const LOG_TARGET: &str = "lspower::log";
+
+/// Layer which logs the start and end of every message handler, with its method and ID.
+///
+/// Records are logged at the `debug` level with the [`LOG_TARGET`] target, so that they can be
+/// enabled separately from the rest of the logs. Each record carries the `method` and, for
+/// requests, the `id` as [key-value pairs], and the end record also carries the time the handler
+/// took as `elapsed_ms`. The message repeats them for loggers which ignore key-value pairs, e.g.
+/// `textDocument/hover id=3: finished in 1.2ms`.
+///
+/// [key-value pairs]: https://docs.rs/log/0.4/log/kv/index.html
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogLayer;
+
+impl<S> Layer<S> for LogLayer {
+    type Service = Log<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Log { inner }
+    }
+}
+
+/// Service created by [`LogLayer`].
+#[derive(Clone, Debug)]
+pub struct Log<S> {
+    inner: S,
+}
+
+impl<S> Service<Incoming> for Log<S>
+where
+    S: Service<Incoming, Response = Option<Outgoing>>,
+    S::Future: Send + 'static,
+{
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+    type Response = Option<Outgoing>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, msg: Incoming) -> Self::Future {
+        let (method, id) = match describe(&msg) {
+            Some((method, id)) => (method, id.map(|id| id.to_string())),
+            None => return self.inner.call(msg).boxed(),
+        };
+        let label = match id {
+            Some(ref id) => format!("{} id={}", method, id),
+            None => method.clone(),
+        };
+
+        log_handler(&method, id.as_deref(), None, format_args!("{}: started", label));
+        let start = Instant::now();
+        self.inner
+            .call(msg)
+            .inspect(move |result| {
+                let elapsed = start.elapsed();
+                let outcome = if result.is_ok() { "finished in" } else { "failed after" };
+                let message = format_args!("{}: {} {:?}", label, outcome, elapsed);
+                log_handler(&method, id.as_deref(), Some(elapsed), message);
+            })
+            .boxed()
+    }
+}
+
+/// Logs a record of [`LogLayer`], with the key-value pairs which apply to it.
+fn log_handler(method: &str, id: Option<&str>, elapsed: Option<Duration>, message: fmt::Arguments) {
+    let level = log::Level::Debug;
+    if !log::log_enabled!(target: LOG_TARGET, level) {
+        return;
+    }
+
+    let mut kvs: Vec<(&str, log::kv::Value)> = vec![("method", method.into())];
+    kvs.extend(id.map(|id| ("id", id.into())));
+    kvs.extend(elapsed.map(|elapsed| ("elapsed_ms", (elapsed.as_secs_f64() * 1000.0).into())));
+    let kvs = kvs.as_slice();
+    let record = log::Record::builder()
+        .args(message)
+        .level(level)
+        .target(LOG_TARGET)
+        .module_path_static(Some(module_path!()))
+        .file_static(Some(file!()))
+        .line(Some(line!()))
+        .key_values(&kvs)
+        .build();
+    log::logger().log(&record);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jsonrpc, LspService};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tower_test::mock::Spawn;
+
+    #[derive(Debug)]
+    struct Panicking;
+
+    #[async_trait]
+    impl crate::LanguageServer for Panicking {
+        async fn initialize(&self, _: lsp::InitializeParams) -> jsonrpc::Result<lsp::InitializeResult> {
+            Ok(lsp::InitializeResult::default())
+        }
+
+        async fn shutdown(&self) -> jsonrpc::Result<()> {
+            panic!("shutdown failed")
+        }
+
+        async fn did_save(&self, _: lsp::DidSaveTextDocumentParams) {
+            panic!("save failed")
+        }
+    }
+
+    fn incoming(value: serde_json::Value) -> Incoming {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn layers_compose() {
+        let latencies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = latencies.clone();
+        let latency = LatencyLayer::new(move |method: &str, _| recorded.lock().unwrap().push(method.to_owned()));
+
+        let (service, _) = LspService::new(|_| Panicking);
+        let service = LogLayer.layer(latency.layer(CatchPanicLayer.layer(service)));
+        let mut service = Spawn::new(service);
+
+        let params = json!({ "capabilities": {} });
+        let initialize = incoming(json!({ "jsonrpc": "2.0", "method": "initialize", "params": params, "id": 1 }));
+        let raw = json!({ "jsonrpc": "2.0", "result": { "capabilities": {} }, "id": 1 });
+        let ok = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert_eq!(service.call(initialize).await, Ok(Some(ok)));
+
+        let did_save = json!({ "jsonrpc": "2.0", "method": "textDocument/didSave", "params": {
+            "textDocument": { "uri": "inmemory::///test" }
+        } });
+        assert_eq!(service.call(incoming(did_save)).await, Ok(None));
+
+        let shutdown = incoming(json!({ "jsonrpc": "2.0", "method": "shutdown", "id": 2 }));
+        let raw = json!({ "jsonrpc": "2.0", "error": { "code": -32603, "message": "Internal error" }, "id": 2 });
+        let err = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.call(shutdown).await, Ok(Some(err)));
+
+        let response = Incoming::Response(Response::ok(Id::Number(0), json!(null)));
+        assert_eq!(service.call(response).await, Ok(None));
+
+        let methods = latencies.lock().unwrap().clone();
+        assert_eq!(methods, vec!["initialize", "textDocument/didSave", "shutdown"]);
+    }
+
+    #[tokio::test]
+    async fn catches_panics_in_call() {
+        #[derive(Debug)]
+        struct PanicsOnCall;
+
+        impl Service<Incoming> for PanicsOnCall {
+            type Error = String;
+            type Future = future::Ready<Result<Option<Outgoing>, String>>;
+            type Response = Option<Outgoing>;
+
+            fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, _: Incoming) -> Self::Future {
+                panic!("call failed")
+            }
+        }
+
+        let mut service = CatchPanicLayer.layer(PanicsOnCall);
+        let request = incoming(json!({ "jsonrpc": "2.0", "method": "custom", "params": null, "id": "a" }));
+        let error = Response::error(Some(Id::String("a".into())), Error::internal_error());
+        assert_eq!(service.call(request).await, Ok(Some(Outgoing::Response(error))));
+    }
+}
@@ -10,15 +10,23 @@ pub extern crate lsp;
 
 mod client;
 mod codec;
+pub mod documents;
 pub mod jsonrpc;
+pub mod layers;
+mod line_index;
+pub mod metrics;
+pub mod recording;
 mod server;
 mod service;
+mod time;
 mod transport;
 
 pub use self::{
-    client::{CancellationToken, Client, TokenCanceller},
-    service::{ExitedError, LspService, MessageStream},
-    transport::Server,
+    client::{CancellationToken, Client, ClientError, Progress, TokenCanceller},
+    codec::Framing,
+    line_index::{LineIndex, PositionEncoding},
+    service::{ExitStatus, ExitedError, LspService, LspServiceBuilder, MessageStream},
+    transport::{ServeError, Server},
 };
 pub use async_trait::async_trait;
 use auto_impl::auto_impl;
@@ -138,6 +146,108 @@ pub trait LanguageServer: Send + Sync + 'static {
         Err(crate::jsonrpc::Error::method_not_found())
     }
 
+    /// The [`workspace/willCreateFiles`] request is sent from the client to the server before
+    /// files are actually created as long as the creation is triggered from within the client.
+    ///
+    /// The request can return a `WorkspaceEdit` which will be applied to the workspace before the
+    /// files are created. Please note that clients might drop results if computing the edit took
+    /// too long or if a server constantly fails on this request.
+    ///
+    /// [`workspace/willCreateFiles`]: https://microsoft.github.io/language-server-protocol/specification#workspace_willCreateFiles
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.16.0.
+    #[rpc(name = "workspace/willCreateFiles")]
+    async fn will_create_files(
+        &self,
+        _params: lsp::CreateFilesParams,
+    ) -> crate::jsonrpc::Result<Option<lsp::WorkspaceEdit>> {
+        log::error!("Got a workspace/willCreateFiles request, but it is not implemented");
+        Err(crate::jsonrpc::Error::method_not_found())
+    }
+
+    /// The [`workspace/didCreateFiles`] notification is sent from the client to the server when
+    /// files were created from within the client.
+    ///
+    /// [`workspace/didCreateFiles`]: https://microsoft.github.io/language-server-protocol/specification#workspace_didCreateFiles
+    ///
+    /// # Compatibility
+    ///
+    /// This notification was introduced in specification version 3.16.0.
+    #[rpc(name = "workspace/didCreateFiles")]
+    async fn did_create_files(&self, _params: lsp::CreateFilesParams) {
+        log::warn!("Got a workspace/didCreateFiles notification, but it is not implemented");
+    }
+
+    /// The [`workspace/willRenameFiles`] request is sent from the client to the server before
+    /// files are actually renamed as long as the rename is triggered from within the client.
+    ///
+    /// The request can return a `WorkspaceEdit` which will be applied to the workspace before the
+    /// files are renamed. Please note that clients might drop results if computing the edit took
+    /// too long or if a server constantly fails on this request.
+    ///
+    /// [`workspace/willRenameFiles`]: https://microsoft.github.io/language-server-protocol/specification#workspace_willRenameFiles
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.16.0.
+    #[rpc(name = "workspace/willRenameFiles")]
+    async fn will_rename_files(
+        &self,
+        _params: lsp::RenameFilesParams,
+    ) -> crate::jsonrpc::Result<Option<lsp::WorkspaceEdit>> {
+        log::error!("Got a workspace/willRenameFiles request, but it is not implemented");
+        Err(crate::jsonrpc::Error::method_not_found())
+    }
+
+    /// The [`workspace/didRenameFiles`] notification is sent from the client to the server when
+    /// files were renamed from within the client.
+    ///
+    /// [`workspace/didRenameFiles`]: https://microsoft.github.io/language-server-protocol/specification#workspace_didRenameFiles
+    ///
+    /// # Compatibility
+    ///
+    /// This notification was introduced in specification version 3.16.0.
+    #[rpc(name = "workspace/didRenameFiles")]
+    async fn did_rename_files(&self, _params: lsp::RenameFilesParams) {
+        log::warn!("Got a workspace/didRenameFiles notification, but it is not implemented");
+    }
+
+    /// The [`workspace/willDeleteFiles`] request is sent from the client to the server before
+    /// files are actually deleted as long as the deletion is triggered from within the client.
+    ///
+    /// The request can return a `WorkspaceEdit` which will be applied to the workspace before the
+    /// files are deleted. Please note that clients might drop results if computing the edit took
+    /// too long or if a server constantly fails on this request.
+    ///
+    /// [`workspace/willDeleteFiles`]: https://microsoft.github.io/language-server-protocol/specification#workspace_willDeleteFiles
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.16.0.
+    #[rpc(name = "workspace/willDeleteFiles")]
+    async fn will_delete_files(
+        &self,
+        _params: lsp::DeleteFilesParams,
+    ) -> crate::jsonrpc::Result<Option<lsp::WorkspaceEdit>> {
+        log::error!("Got a workspace/willDeleteFiles request, but it is not implemented");
+        Err(crate::jsonrpc::Error::method_not_found())
+    }
+
+    /// The [`workspace/didDeleteFiles`] notification is sent from the client to the server when
+    /// files were deleted from within the client.
+    ///
+    /// [`workspace/didDeleteFiles`]: https://microsoft.github.io/language-server-protocol/specification#workspace_didDeleteFiles
+    ///
+    /// # Compatibility
+    ///
+    /// This notification was introduced in specification version 3.16.0.
+    #[rpc(name = "workspace/didDeleteFiles")]
+    async fn did_delete_files(&self, _params: lsp::DeleteFilesParams) {
+        log::warn!("Got a workspace/didDeleteFiles notification, but it is not implemented");
+    }
+
     /// The [`textDocument/didOpen`] notification is sent from the client to the server to signal
     /// that a new text document has been opened by the client.
     ///
@@ -739,13 +849,6 @@ pub trait LanguageServer: Send + Sync + 'static {
         Err(crate::jsonrpc::Error::method_not_found())
     }
 
-    /// [`workspace/semanticTokens/full`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#textDocument_semanticTokens
-    #[rpc(name = "workspace/semanticTokens/refresh")]
-    async fn semantic_tokens_refresh(&self) -> crate::jsonrpc::Result<()> {
-        log::error!("Got a workspace/semanticTokens/refresh request, but it is not implemented");
-        Err(crate::jsonrpc::Error::method_not_found())
-    }
-
     /// [`codeAction/resolve`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#codeAction_resolve
     #[rpc(name = "codeAction/resolve")]
     async fn code_action_resolve(&self, _params: lsp::CodeAction) -> crate::jsonrpc::Result<lsp::CodeAction> {
@@ -753,6 +856,58 @@ pub trait LanguageServer: Send + Sync + 'static {
         Err(crate::jsonrpc::Error::method_not_found())
     }
 
+    /// The [`textDocument/linkedEditingRange`] request is sent from the client to the server to
+    /// return for a given position in a document the range of the symbol at the position and all
+    /// ranges that have the same content.
+    ///
+    /// Optionally a word pattern can be returned to describe valid contents. A rename to one of the
+    /// ranges can be applied to all other ranges if the new content is valid.
+    ///
+    /// [`textDocument/linkedEditingRange`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#textDocument_linkedEditingRange
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.16.0.
+    #[rpc(name = "textDocument/linkedEditingRange")]
+    async fn linked_editing_range(
+        &self,
+        _params: lsp::LinkedEditingRangeParams,
+    ) -> crate::jsonrpc::Result<Option<lsp::LinkedEditingRanges>> {
+        log::error!("Got a textDocument/linkedEditingRange request, but it is not implemented");
+        Err(crate::jsonrpc::Error::method_not_found())
+    }
+
+    /// The [`textDocument/moniker`] request is sent from the client to the server to get the symbol
+    /// monikers for a given text document position.
+    ///
+    /// An array of `Moniker` types is returned as response to indicate possible monikers at the
+    /// given location. If no monikers can be calculated, `Some(vec![])` or `None` should be
+    /// returned.
+    ///
+    /// [`textDocument/moniker`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#textDocument_moniker
+    ///
+    /// # Compatibility
+    ///
+    /// This request was introduced in specification version 3.16.0.
+    #[rpc(name = "textDocument/moniker")]
+    async fn moniker(&self, _params: lsp::MonikerParams) -> crate::jsonrpc::Result<Option<Vec<lsp::Moniker>>> {
+        log::error!("Got a textDocument/moniker request, but it is not implemented");
+        Err(crate::jsonrpc::Error::method_not_found())
+    }
+
+    /// The [`window/workDoneProgress/cancel`] notification is sent from the client to the server
+    /// to cancel a progress initiated on the server side using `window/workDoneProgress/create`.
+    ///
+    /// [`window/workDoneProgress/cancel`]: https://microsoft.github.io/language-server-protocol/specifications/specification-3-16/#window_workDoneProgress_cancel
+    ///
+    /// # Compatibility
+    ///
+    /// This notification was introduced in specification version 3.15.0.
+    #[rpc(name = "window/workDoneProgress/cancel")]
+    async fn work_done_progress_cancel(&self, _params: lsp::WorkDoneProgressCancelParams) {
+        log::warn!("Got a window/workDoneProgress/cancel notification, but it is not implemented");
+    }
+
     /// This handler can be used to respond to all requests that are not handled by built in request
     /// handlers.
     async fn request_else(
@@ -766,6 +921,20 @@ pub trait LanguageServer: Send + Sync + 'static {
         );
         Err(crate::jsonrpc::Error::method_not_found())
     }
+
+    /// This handler can be used to respond to all notifications that are not handled by built in
+    /// notification handlers.
+    ///
+    /// Notifications whose method name starts with `$/` are protocol-dependent and are silently
+    /// ignored by the default implementation, as required by the specification.
+    async fn notification_else(&self, method: &str, _params: Option<serde_json::Value>) {
+        if !method.starts_with("$/") {
+            log::warn!(
+                "Got a {} notification, but LanguageServer::notification_else is not implemented",
+                method
+            );
+        }
+    }
 }
 
 #[cfg(test)]
@@ -818,11 +987,22 @@ mod tests {
                 "id": 1,
             }))
         }
+
+        pub(super) fn notification<I: Serialize, O: DeserializeOwned>(
+            method: &str,
+            params: I,
+        ) -> Result<O, serde_json::Error> {
+            serde_json::from_value(json!({
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": params,
+            }))
+        }
     }
 
     #[tokio::test]
     async fn initialize() {
-        let (service, _) = LspService::new(|_| Mock::default());
+        let (service, _) = LspService::new(|_| Mock);
         let mut service = Spawn::new(service);
 
         helper::initialize(&mut service).await;
@@ -830,7 +1010,7 @@ mod tests {
 
     #[tokio::test]
     async fn initialized() {
-        let (service, _) = LspService::new(|_| Mock::default());
+        let (service, _) = LspService::new(|_| Mock);
         let mut service = Spawn::new(service);
 
         helper::initialize(&mut service).await;
@@ -843,7 +1023,7 @@ mod tests {
 
     #[tokio::test]
     async fn shutdown() {
-        let (service, _) = LspService::new(|_| Mock::default());
+        let (service, _) = LspService::new(|_| Mock);
         let mut service = Spawn::new(service);
 
         helper::initialize(&mut service).await;
@@ -865,7 +1045,7 @@ mod tests {
 
         #[tokio::test]
         async fn incoming_calls() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -895,7 +1075,7 @@ mod tests {
 
         #[tokio::test]
         async fn outgoing_calls() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -932,7 +1112,7 @@ mod tests {
 
         #[tokio::test]
         async fn resolve() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -956,7 +1136,7 @@ mod tests {
 
         #[tokio::test]
         async fn resolve() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -992,7 +1172,7 @@ mod tests {
 
                 #[tokio::test]
                 async fn delta() {
-                    let (service, _) = LspService::new(|_| Mock::default());
+                    let (service, _) = LspService::new(|_| Mock);
                     let mut service = Spawn::new(service);
 
                     super::helper::initialize(&mut service).await;
@@ -1017,7 +1197,7 @@ mod tests {
 
             #[tokio::test]
             async fn full() {
-                let (service, _) = LspService::new(|_| Mock::default());
+                let (service, _) = LspService::new(|_| Mock);
                 let mut service = Spawn::new(service);
 
                 super::helper::initialize(&mut service).await;
@@ -1040,7 +1220,7 @@ mod tests {
 
             #[tokio::test]
             async fn range() {
-                let (service, _) = LspService::new(|_| Mock::default());
+                let (service, _) = LspService::new(|_| Mock);
                 let mut service = Spawn::new(service);
 
                 super::helper::initialize(&mut service).await;
@@ -1061,27 +1241,11 @@ mod tests {
                     Ok(Some(Outgoing::Response(response)))
                 );
             }
-
-            #[tokio::test]
-            async fn refresh() {
-                let (service, _) = LspService::new(|_| Mock::default());
-                let mut service = Spawn::new(service);
-
-                super::helper::initialize(&mut service).await;
-
-                let request: Incoming = helper::request("textDocument/semanticTokens/refresh", ()).unwrap();
-                let response = Response::error(Some(Id::Number(1)), Error::method_not_found());
-                assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
-                assert_eq!(
-                    service.call(request.clone()).await,
-                    Ok(Some(Outgoing::Response(response)))
-                );
-            }
         }
 
         #[tokio::test]
         async fn code_action() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1106,7 +1270,7 @@ mod tests {
 
         #[tokio::test]
         async fn code_lens() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1129,7 +1293,7 @@ mod tests {
 
         #[tokio::test]
         async fn code_lens_resolve() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1150,7 +1314,7 @@ mod tests {
 
         #[tokio::test]
         async fn color_presentation() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1180,7 +1344,7 @@ mod tests {
 
         #[tokio::test]
         async fn completion() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1207,7 +1371,7 @@ mod tests {
 
         #[tokio::test]
         async fn declaration() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1233,7 +1397,7 @@ mod tests {
 
         #[tokio::test]
         async fn definition() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1259,7 +1423,7 @@ mod tests {
 
         #[tokio::test]
         async fn did_change() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1278,7 +1442,7 @@ mod tests {
 
         #[tokio::test]
         async fn did_close() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1295,7 +1459,7 @@ mod tests {
 
         #[tokio::test]
         async fn did_open() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1315,7 +1479,7 @@ mod tests {
 
         #[tokio::test]
         async fn did_save() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1333,7 +1497,7 @@ mod tests {
 
         #[tokio::test]
         async fn document_color() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1356,7 +1520,7 @@ mod tests {
 
         #[tokio::test]
         async fn document_highlight() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1382,7 +1546,7 @@ mod tests {
 
         #[tokio::test]
         async fn document_link() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1405,7 +1569,7 @@ mod tests {
 
         #[tokio::test]
         async fn document_link_resolve() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1427,7 +1591,7 @@ mod tests {
 
         #[tokio::test]
         async fn document_symbol() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1450,7 +1614,7 @@ mod tests {
 
         #[tokio::test]
         async fn folding_range() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1473,7 +1637,7 @@ mod tests {
 
         #[tokio::test]
         async fn formatting() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1496,7 +1660,7 @@ mod tests {
 
         #[tokio::test]
         async fn hover() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1521,7 +1685,7 @@ mod tests {
 
         #[tokio::test]
         async fn implementation() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1545,9 +1709,132 @@ mod tests {
             );
         }
 
+        #[tokio::test]
+        async fn linked_editing_range() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::LinkedEditingRangeParams {
+                text_document_position_params: lsp::TextDocumentPositionParams {
+                    text_document: lsp::TextDocumentIdentifier {
+                        uri: lsp::Url::parse("inmemory::///test").unwrap(),
+                    },
+                    position: Default::default(),
+                },
+                work_done_progress_params: Default::default(),
+            };
+            let request: Incoming = helper::request("textDocument/linkedEditingRange", params).unwrap();
+            let response = Response::error(Some(Id::Number(1)), Error::method_not_found());
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(
+                service.call(request.clone()).await,
+                Ok(Some(Outgoing::Response(response)))
+            );
+        }
+
+        #[tokio::test]
+        async fn moniker() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::MonikerParams {
+                text_document_position_params: lsp::TextDocumentPositionParams {
+                    text_document: lsp::TextDocumentIdentifier {
+                        uri: lsp::Url::parse("inmemory::///test").unwrap(),
+                    },
+                    position: Default::default(),
+                },
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            };
+            let request: Incoming = helper::request("textDocument/moniker", params).unwrap();
+            let response = Response::error(Some(Id::Number(1)), Error::method_not_found());
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(
+                service.call(request.clone()).await,
+                Ok(Some(Outgoing::Response(response)))
+            );
+        }
+
+        #[tokio::test]
+        async fn notification_else() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = None::<serde_json::Value>;
+            let request: Incoming = helper::notification("foo/bar", params).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request.clone()).await, Ok(None));
+        }
+
+        #[tokio::test]
+        async fn notification_else_dispatch() {
+            use std::sync::{Arc, Mutex};
+
+            #[derive(Debug, Default)]
+            struct Recorder(Mutex<Vec<(String, Option<serde_json::Value>)>>);
+
+            #[async_trait]
+            impl crate::LanguageServer for Recorder {
+                async fn initialize(&self, _: lsp::InitializeParams) -> crate::jsonrpc::Result<lsp::InitializeResult> {
+                    Ok(lsp::InitializeResult::default())
+                }
+
+                async fn shutdown(&self) -> crate::jsonrpc::Result<()> {
+                    Ok(())
+                }
+
+                async fn notification_else(&self, method: &str, params: Option<serde_json::Value>) {
+                    self.0.lock().unwrap().push((method.into(), params));
+                }
+            }
+
+            let recorder = Arc::new(Recorder::default());
+            let (service, _) = LspService::new(|_| recorder.clone());
+            let mut service = Spawn::new(service);
+
+            let params = json!({ "uri": "inmemory::///test" });
+            let request: Incoming = helper::notification("foo/bar", params.clone()).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request.clone()).await, Ok(None));
+            assert!(recorder.0.lock().unwrap().is_empty());
+
+            super::helper::initialize(&mut service).await;
+
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request).await, Ok(None));
+
+            let request: Incoming = helper::notification("$/foo", None::<serde_json::Value>).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request).await, Ok(None));
+
+            // A known request method sent without an ID is passed on as it is.
+            let hover = json!({
+                "textDocument": { "uri": "inmemory::///test" },
+                "position": { "line": 0, "character": 0 },
+            });
+            let request: Incoming = helper::notification("textDocument/hover", hover.clone()).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request).await, Ok(None));
+
+            let received = recorder.0.lock().unwrap().clone();
+            let expected = vec![
+                ("foo/bar".into(), Some(params)),
+                ("$/foo".into(), None),
+                ("textDocument/hover".into(), Some(hover)),
+            ];
+            assert_eq!(received, expected);
+        }
+
         #[tokio::test]
         async fn on_type_formatting() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1573,7 +1860,7 @@ mod tests {
 
         #[tokio::test]
         async fn prepare_call_hierarchy() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1598,7 +1885,7 @@ mod tests {
 
         #[tokio::test]
         async fn prepare_rename() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1620,7 +1907,7 @@ mod tests {
 
         #[tokio::test]
         async fn range_formatting() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1644,7 +1931,7 @@ mod tests {
 
         #[tokio::test]
         async fn references() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1673,7 +1960,7 @@ mod tests {
 
         #[tokio::test]
         async fn rename() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1699,7 +1986,7 @@ mod tests {
 
         #[tokio::test]
         async fn request_else() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1716,7 +2003,7 @@ mod tests {
 
         #[tokio::test]
         async fn selection_range() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1740,7 +2027,7 @@ mod tests {
 
         #[tokio::test]
         async fn signature_help() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1766,7 +2053,7 @@ mod tests {
 
         #[tokio::test]
         async fn type_definition() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1792,7 +2079,7 @@ mod tests {
 
         #[tokio::test]
         async fn will_save() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1810,7 +2097,7 @@ mod tests {
 
         #[tokio::test]
         async fn will_save_wait_until() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1831,6 +2118,28 @@ mod tests {
         }
     }
 
+    mod window {
+        use super::*;
+        use crate::jsonrpc::Incoming;
+        use std::task::Poll;
+        use tower_test::mock::Spawn;
+
+        #[tokio::test]
+        async fn work_done_progress_cancel() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::WorkDoneProgressCancelParams {
+                token: lsp::NumberOrString::Number(1),
+            };
+            let request: Incoming = helper::notification("window/workDoneProgress/cancel", params).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request.clone()).await, Ok(None));
+        }
+    }
+
     mod workspace {
         use super::*;
         use crate::jsonrpc::{Error, Id, Incoming, Outgoing, Response};
@@ -1840,7 +2149,7 @@ mod tests {
 
         #[tokio::test]
         async fn did_change_configuration() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1853,7 +2162,7 @@ mod tests {
 
         #[tokio::test]
         async fn did_change_watched_files() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1868,7 +2177,7 @@ mod tests {
 
         #[tokio::test]
         async fn did_change_workspace_folders() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1879,9 +2188,48 @@ mod tests {
             assert_eq!(service.call(request.clone()).await, Ok(None));
         }
 
+        #[tokio::test]
+        async fn did_create_files() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::CreateFilesParams::default();
+            let request: Incoming = helper::notification("workspace/didCreateFiles", params).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request.clone()).await, Ok(None));
+        }
+
+        #[tokio::test]
+        async fn did_delete_files() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::DeleteFilesParams::default();
+            let request: Incoming = helper::notification("workspace/didDeleteFiles", params).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request.clone()).await, Ok(None));
+        }
+
+        #[tokio::test]
+        async fn did_rename_files() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::RenameFilesParams::default();
+            let request: Incoming = helper::notification("workspace/didRenameFiles", params).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request.clone()).await, Ok(None));
+        }
+
         #[tokio::test]
         async fn execute_command() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1898,7 +2246,7 @@ mod tests {
 
         #[tokio::test]
         async fn symbol() {
-            let (service, _) = LspService::new(|_| Mock::default());
+            let (service, _) = LspService::new(|_| Mock);
             let mut service = Spawn::new(service);
 
             super::helper::initialize(&mut service).await;
@@ -1912,5 +2260,56 @@ mod tests {
                 Ok(Some(Outgoing::Response(response)))
             );
         }
+
+        #[tokio::test]
+        async fn will_create_files() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::CreateFilesParams::default();
+            let request: Incoming = helper::request("workspace/willCreateFiles", params).unwrap();
+            let response = Response::error(Some(Id::Number(1)), Error::method_not_found());
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(
+                service.call(request.clone()).await,
+                Ok(Some(Outgoing::Response(response)))
+            );
+        }
+
+        #[tokio::test]
+        async fn will_delete_files() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::DeleteFilesParams::default();
+            let request: Incoming = helper::request("workspace/willDeleteFiles", params).unwrap();
+            let response = Response::error(Some(Id::Number(1)), Error::method_not_found());
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(
+                service.call(request.clone()).await,
+                Ok(Some(Outgoing::Response(response)))
+            );
+        }
+
+        #[tokio::test]
+        async fn will_rename_files() {
+            let (service, _) = LspService::new(|_| Mock);
+            let mut service = Spawn::new(service);
+
+            super::helper::initialize(&mut service).await;
+
+            let params = lsp::RenameFilesParams::default();
+            let request: Incoming = helper::request("workspace/willRenameFiles", params).unwrap();
+            let response = Response::error(Some(Id::Number(1)), Error::method_not_found());
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(
+                service.call(request.clone()).await,
+                Ok(Some(Outgoing::Response(response)))
+            );
+        }
     }
 }
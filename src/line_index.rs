@@ -0,0 +1,270 @@
+//! Conversions between byte offsets, `char` offsets and LSP positions.
+
+use std::ops::Range;
+
+/// Unit in which the `character` field of an [`lsp::Position`] is counted.
+///
+/// Clients which support LSP 3.17 can advertise the encodings they understand in the
+/// `general.positionEncodings` client capability. Older clients only understand
+/// [`PositionEncoding::Utf16`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum PositionEncoding {
+    /// Characters are counted in UTF-8 code units, i.e. bytes.
+    Utf8,
+    /// Characters are counted in UTF-16 code units. This is the default encoding.
+    #[default]
+    Utf16,
+    /// Characters are counted in Unicode scalar values, i.e. Rust `char`s.
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Returns the name of the encoding as used by the specification, e.g. `"utf-16"`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PositionEncoding::Utf8 => "utf-8",
+            PositionEncoding::Utf16 => "utf-16",
+            PositionEncoding::Utf32 => "utf-32",
+        }
+    }
+
+    fn len(self, c: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8(),
+            PositionEncoding::Utf16 => c.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Line {
+    /// Byte offset of the first character of the line.
+    start: usize,
+    /// `char` offset of the first character of the line.
+    start_char: usize,
+    /// Length in bytes of the line, excluding its terminator.
+    len: usize,
+    /// Non-ASCII characters of the line, with their byte offset relative to `start`.
+    wide_chars: Vec<(usize, char)>,
+}
+
+impl Line {
+    /// Converts a byte offset relative to the line start into `encoding` units.
+    fn units(&self, col: usize, encoding: PositionEncoding) -> usize {
+        let mut units = col;
+        for &(start, c) in self.wide_chars.iter().take_while(|(start, _)| *start < col) {
+            if start + c.len_utf8() > col {
+                // The offset points inside the character, count it as pointing at its start.
+                return units - (col - start);
+            }
+            units -= c.len_utf8() - encoding.len(c);
+        }
+        units
+    }
+
+    /// Converts a column counted in `encoding` units into a byte offset relative to the line start.
+    fn bytes(&self, units: usize, encoding: PositionEncoding) -> usize {
+        let mut extra = 0;
+        for &(start, c) in &self.wide_chars {
+            let unit_start = start - extra;
+            if unit_start >= units {
+                break;
+            }
+            if unit_start + encoding.len(c) > units {
+                // The column points inside the character, resolve it to the character's start.
+                return start;
+            }
+            extra += c.len_utf8() - encoding.len(c);
+        }
+        units + extra
+    }
+}
+
+/// Index of the line starts of a text, for converting between offsets and LSP positions.
+///
+/// Byte offsets are what Rust strings and most parsers work with, while [`lsp::Position`] counts
+/// characters in the [`PositionEncoding`] negotiated with the client, which is UTF-16 unless the
+/// client advertised otherwise. Use [`Client::position_encoding`] to build an index which matches
+/// the client:
+///
+/// ```rust,ignore
+/// let document = documents.get(&uri).unwrap();
+/// let index = LineIndex::with_encoding(document.text(), client.position_encoding());
+/// let diagnostic = lsp::Diagnostic::new_simple(index.range(error.span()), error.to_string());
+/// client.publish_diagnostics(uri, vec![diagnostic], Some(document.version())).await;
+/// ```
+///
+/// Lines are terminated by `\n` or `\r\n`. Positions past the end of a line resolve to the end of
+/// that line, and positions past the last line resolve to the end of the text. Byte offsets past
+/// the end of the text are clamped as well.
+///
+/// [`Client::position_encoding`]: crate::Client::position_encoding
+#[derive(Debug)]
+pub struct LineIndex {
+    encoding: PositionEncoding,
+    len: usize,
+    len_chars: usize,
+    lines: Vec<Line>,
+}
+
+impl LineIndex {
+    /// Creates an index for `text` which uses the default UTF-16 position encoding.
+    pub fn new(text: &str) -> Self {
+        LineIndex::with_encoding(text, PositionEncoding::Utf16)
+    }
+
+    /// Creates an index for `text` which uses the given position encoding.
+    pub fn with_encoding(text: &str, encoding: PositionEncoding) -> Self {
+        let mut lines = Vec::new();
+        let mut line = Line {
+            start: 0,
+            start_char: 0,
+            len: 0,
+            wide_chars: Vec::new(),
+        };
+        let mut chars = 0;
+        for (i, c) in text.char_indices() {
+            chars += 1;
+            if c == '\n' {
+                let end = if text[.. i].ends_with('\r') { i - 1 } else { i };
+                line.len = end - line.start;
+                let next = Line {
+                    start: i + 1,
+                    start_char: chars,
+                    len: 0,
+                    wide_chars: Vec::new(),
+                };
+                lines.push(std::mem::replace(&mut line, next));
+            } else if !c.is_ascii() {
+                line.wide_chars.push((i - line.start, c));
+            }
+        }
+        line.len = text.len() - line.start;
+        lines.push(line);
+
+        LineIndex {
+            encoding,
+            len: text.len(),
+            len_chars: chars,
+            lines,
+        }
+    }
+
+    /// Returns the position encoding used by this index.
+    pub fn encoding(&self) -> PositionEncoding {
+        self.encoding
+    }
+
+    /// Converts a byte offset into a position.
+    pub fn position(&self, offset: usize) -> lsp::Position {
+        let offset = offset.min(self.len);
+        let line = self.lines.partition_point(|line| line.start <= offset) - 1;
+        let col = (offset - self.lines[line].start).min(self.lines[line].len);
+        let character = self.lines[line].units(col, self.encoding);
+        lsp::Position::new(line as u32, character as u32)
+    }
+
+    /// Converts a position into a byte offset.
+    pub fn offset(&self, position: lsp::Position) -> usize {
+        match self.lines.get(position.line as usize) {
+            None => self.len,
+            Some(line) => {
+                let col = line.bytes(position.character as usize, self.encoding);
+                line.start + col.min(line.len)
+            },
+        }
+    }
+
+    /// Converts a range of byte offsets into an LSP range.
+    pub fn range(&self, range: Range<usize>) -> lsp::Range {
+        lsp::Range::new(self.position(range.start), self.position(range.end))
+    }
+
+    /// Converts an LSP range into a range of byte offsets.
+    pub fn offset_range(&self, range: lsp::Range) -> Range<usize> {
+        let start = self.offset(range.start);
+        start .. self.offset(range.end).max(start)
+    }
+
+    /// Converts a byte offset into a `char` offset.
+    pub fn char_offset(&self, offset: usize) -> usize {
+        let offset = offset.min(self.len);
+        let line = &self.lines[self.lines.partition_point(|line| line.start <= offset) - 1];
+        line.start_char + line.units(offset - line.start, PositionEncoding::Utf32)
+    }
+
+    /// Converts a `char` offset into a byte offset.
+    pub fn byte_offset(&self, char_offset: usize) -> usize {
+        let char_offset = char_offset.min(self.len_chars);
+        let line = &self.lines[self.lines.partition_point(|line| line.start_char <= char_offset) - 1];
+        line.start + line.bytes(char_offset - line.start_char, PositionEncoding::Utf32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `é` is two UTF-8 bytes and one UTF-16 code unit, `𐐀` is four bytes and two code units.
+    const TEXT: &str = "aé𐐀b\r\n\n𐐀x";
+
+    #[test]
+    fn position_encodings() {
+        let expected = [
+            (PositionEncoding::Utf8, [0, 1, 3, 7, 8]),
+            (PositionEncoding::Utf16, [0, 1, 2, 4, 5]),
+            (PositionEncoding::Utf32, [0, 1, 2, 3, 4]),
+        ];
+        for (encoding, characters) in expected.iter() {
+            let index = LineIndex::with_encoding(TEXT, *encoding);
+            for (offset, character) in [0, 1, 3, 7, 8].iter().zip(characters.iter()) {
+                let position = lsp::Position::new(0, *character);
+                assert_eq!(index.position(*offset), position, "{:?} {}", encoding, offset);
+                assert_eq!(index.offset(position), *offset, "{:?} {}", encoding, offset);
+            }
+            assert_eq!(index.position(14), lsp::Position::new(2, 0));
+            assert_eq!(index.offset(lsp::Position::new(2, 0)), 11);
+        }
+    }
+
+    #[test]
+    fn line_terminators() {
+        let index = LineIndex::new(TEXT);
+        assert_eq!(index.position(9), lsp::Position::new(0, 5));
+        assert_eq!(index.position(10), lsp::Position::new(1, 0));
+        assert_eq!(index.position(11), lsp::Position::new(2, 0));
+        assert_eq!(index.offset(lsp::Position::new(0, 100)), 8);
+        assert_eq!(index.offset(lsp::Position::new(1, 1)), 10);
+        assert_eq!(index.offset(lsp::Position::new(3, 0)), TEXT.len());
+        assert_eq!(index.position(100), lsp::Position::new(2, 3));
+    }
+
+    #[test]
+    fn inside_characters() {
+        let index = LineIndex::new(TEXT);
+        assert_eq!(index.position(4), lsp::Position::new(0, 2));
+        assert_eq!(index.offset(lsp::Position::new(0, 3)), 3);
+    }
+
+    #[test]
+    fn ranges() {
+        let index = LineIndex::new(TEXT);
+        let range = lsp::Range::new(lsp::Position::new(0, 2), lsp::Position::new(2, 2));
+        assert_eq!(index.range(3 .. 15), range);
+        assert_eq!(index.offset_range(range), 3 .. 15);
+    }
+
+    #[test]
+    fn char_offsets() {
+        let index = LineIndex::new(TEXT);
+        let bytes = [0, 1, 3, 7, 8, 9, 10, 11, 15, 16];
+        for (char, &byte) in bytes.iter().enumerate() {
+            assert_eq!(index.char_offset(byte), char);
+            assert_eq!(index.byte_offset(char), byte);
+            assert_eq!(TEXT.char_indices().nth(char).map_or(TEXT.len(), |(i, _)| i), byte);
+        }
+        assert_eq!(index.char_offset(100), 9);
+        assert_eq!(index.byte_offset(100), 16);
+    }
+}
@@ -0,0 +1,242 @@
+//! Request metrics collected by [`LspService`].
+//!
+//! Every [`LspService`] counts the messages it handles per method, along with their errors,
+//! cancellations and latencies. A [`Metrics`] handle returns a [`MetricsSnapshot`] of these
+//! counters on demand, which can be asserted on in tests or sent to the client from a custom
+//! request:
+//!
+//! ```rust,ignore
+//! enum Stats {}
+//!
+//! impl lsp::request::Request for Stats {
+//!     type Params = ();
+//!     type Result = MetricsSnapshot;
+//!     const METHOD: &'static str = "$/myServer/stats";
+//! }
+//!
+//! let builder = LspService::build(|client| Backend { client });
+//! let metrics = builder.metrics();
+//! let (service, messages) = builder
+//!     .custom_method::<Stats, _, _>(move |_, ()| {
+//!         let metrics = metrics.clone();
+//!         async move { Ok(metrics.snapshot()) }
+//!     })
+//!     .finish();
+//! ```
+//!
+//! [`LspService`]: crate::LspService
+
+use crate::jsonrpc::{ClientRequests, ErrorCode, ServerRequests};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Key under which [`MetricsSnapshot::methods`] counts the messages for all methods which neither
+/// the [`LanguageServer`] trait nor a custom method of the [`LspService`] defines, including those
+/// passed to [`LanguageServer::request_else`] and [`LanguageServer::notification_else`].
+///
+/// [`LanguageServer`]: crate::LanguageServer
+/// [`LanguageServer::request_else`]: crate::LanguageServer::request_else
+/// [`LanguageServer::notification_else`]: crate::LanguageServer::notification_else
+/// [`LspService`]: crate::LspService
+pub const UNKNOWN_METHOD: &str = "<unknown>";
+
+/// Upper bounds of the latency histogram buckets, in milliseconds.
+const BUCKET_BOUNDS_MS: [u64; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+
+/// Histogram of the time taken to handle the messages for a method.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LatencyHistogram {
+    /// Upper bounds of the buckets in milliseconds, in increasing order.
+    pub bounds_ms: Vec<u64>,
+    /// Number of messages per bucket. The last element counts the messages slower than the last
+    /// bound, so there is one more element than in `bounds_ms`.
+    pub counts: Vec<u64>,
+    /// Sum of all latencies, in milliseconds.
+    pub total_ms: f64,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let bucket = self.bounds_ms.partition_point(|&bound| (bound as f64) < ms);
+        self.counts[bucket] += 1;
+        self.total_ms += ms;
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            bounds_ms: BUCKET_BOUNDS_MS.to_vec(),
+            counts: vec![0; BUCKET_BOUNDS_MS.len() + 1],
+            total_ms: 0.0,
+        }
+    }
+}
+
+/// Counters for the messages handled for one method.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct MethodMetrics {
+    /// Number of requests and notifications handled.
+    pub count: u64,
+    /// Number of requests answered with an error, not counting cancellations.
+    pub errors: u64,
+    /// Number of messages cancelled by the client or by the server exiting, including those whose
+    /// handler was dropped before it finished, e.g. because the connection closed.
+    pub cancellations: u64,
+    /// Time taken to handle the messages.
+    pub latency: LatencyHistogram,
+}
+
+/// Point-in-time copy of the metrics of an [`LspService`].
+///
+/// [`LspService`]: crate::LspService
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    /// Counters for each method which has been handled at least once, keyed by method name.
+    ///
+    /// Methods without a dedicated handler are all counted under [`UNKNOWN_METHOD`].
+    pub methods: BTreeMap<String, MethodMetrics>,
+    /// Number of client requests whose handler is currently running.
+    pub pending_server_requests: usize,
+    /// Number of requests sent to the client which are still waiting for a response.
+    pub pending_client_requests: usize,
+}
+
+struct Inner {
+    methods: DashMap<String, MethodMetrics>,
+    pending_server: ServerRequests,
+    pending_client: Arc<ClientRequests>,
+}
+
+/// Handle to the metrics of an [`LspService`], returned by [`LspService::metrics`].
+///
+/// This type is cheap to clone; all clones observe the same service.
+///
+/// [`LspService`]: crate::LspService
+/// [`LspService::metrics`]: crate::LspService::metrics
+#[derive(Clone)]
+pub struct Metrics(Arc<Inner>);
+
+impl Metrics {
+    pub(crate) fn new(pending_server: ServerRequests, pending_client: Arc<ClientRequests>) -> Self {
+        Metrics(Arc::new(Inner {
+            methods: DashMap::new(),
+            pending_server,
+            pending_client,
+        }))
+    }
+
+    /// Returns the current values of all metrics.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let methods = self.0.methods.iter();
+        let methods = methods.map(|entry| (entry.key().clone(), entry.value().clone()));
+        MetricsSnapshot {
+            methods: methods.collect(),
+            pending_server_requests: self.0.pending_server.len(),
+            pending_client_requests: self.0.pending_client.len(),
+        }
+    }
+
+    /// Starts measuring the handling of a message for `method`.
+    pub(crate) fn start(&self, method: String) -> Measurement {
+        Measurement {
+            metrics: self.clone(),
+            method,
+            start: Instant::now(),
+            finished: false,
+        }
+    }
+
+    /// Records that a message for `method` was handled in `elapsed`, failing with `error` if any.
+    pub(crate) fn record(&self, method: &str, elapsed: Duration, error: Option<ErrorCode>) {
+        let mut metrics = match self.0.methods.get_mut(method) {
+            Some(metrics) => metrics,
+            None => self.0.methods.entry(method.to_owned()).or_default(),
+        };
+        metrics.count += 1;
+        match error {
+            Some(ErrorCode::RequestCancelled) => metrics.cancellations += 1,
+            Some(_) => metrics.errors += 1,
+            None => {},
+        }
+        metrics.latency.record(elapsed);
+    }
+}
+
+/// Measurement of the handling of a single message, started by [`Metrics::start`].
+///
+/// If it is dropped before [`finish`](Self::finish) is called, the message is counted as
+/// cancelled.
+pub(crate) struct Measurement {
+    metrics: Metrics,
+    method: String,
+    start: Instant,
+    finished: bool,
+}
+
+impl Measurement {
+    /// Records that the message was handled, failing with `error` if any.
+    pub(crate) fn finish(mut self, error: Option<ErrorCode>) {
+        self.finished = true;
+        self.metrics.record(&self.method, self.start.elapsed(), error);
+    }
+}
+
+impl Drop for Measurement {
+    fn drop(&mut self) {
+        if !self.finished {
+            let elapsed = self.start.elapsed();
+            self.metrics.record(&self.method, elapsed, Some(ErrorCode::RequestCancelled));
+        }
+    }
+}
+
+impl Debug for Metrics {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(Metrics))
+            .field("methods", &self.0.methods.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_methods() {
+        let metrics = Metrics::new(ServerRequests::new(), Arc::new(ClientRequests::new()));
+        let hover = "textDocument/hover";
+        metrics.record(hover, Duration::from_micros(500), None);
+        metrics.record(hover, Duration::from_millis(30), Some(ErrorCode::InternalError));
+        metrics.record(hover, Duration::from_secs(10), Some(ErrorCode::RequestCancelled));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.pending_server_requests, 0);
+        assert_eq!(snapshot.pending_client_requests, 0);
+        let hover = &snapshot.methods[hover];
+        assert_eq!((hover.count, hover.errors, hover.cancellations), (3, 1, 1));
+        assert_eq!(hover.latency.counts, vec![1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(hover.latency.total_ms, 10030.5);
+    }
+
+    #[test]
+    fn records_dropped_measurements_as_cancelled() {
+        let metrics = Metrics::new(ServerRequests::new(), Arc::new(ClientRequests::new()));
+        let hover = "textDocument/hover";
+        metrics.start(hover.to_owned()).finish(Some(ErrorCode::InternalError));
+        drop(metrics.start(hover.to_owned()));
+
+        let hover = &metrics.snapshot().methods[hover];
+        assert_eq!((hover.count, hover.errors, hover.cancellations), (2, 1, 1));
+    }
+}
@@ -0,0 +1,573 @@
+//! Recording and replaying of LSP sessions.
+//!
+//! A [`Server`] configured with [`Server::record`] writes every message it reads and writes to a
+//! recording, one JSON [`Record`] per line. Such a recording, e.g. attached to a bug report, can
+//! later be fed back into a fresh [`LspService`] with [`Replay`], which reports every response
+//! that differs from the recorded one:
+//!
+//! ```rust,ignore
+//! let recording = tokio::fs::File::open("session.jsonl").await?;
+//! let (service, messages) = LspService::new(|client| Backend { client });
+//! let report = Replay::new(recording).run(service, messages).await?;
+//! assert!(report.is_match(), "{}", report);
+//! ```
+//!
+//! [`Server`]: crate::Server
+//! [`Server::record`]: crate::Server::record
+//! [`LspService`]: crate::LspService
+
+#[cfg(feature = "runtime-agnostic")]
+use async_codec_lite::{FramedRead, FramedWrite};
+#[cfg(feature = "runtime-agnostic")]
+use futures::io::{AsyncRead, AsyncWrite};
+
+#[cfg(feature = "runtime-tokio")]
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "runtime-tokio")]
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::{
+    codec::{Framing, LanguageServerCodec},
+    jsonrpc::{Incoming, Outgoing},
+    transport::into_io_error,
+};
+use futures::{
+    channel::mpsc,
+    future::{self, Either, TryFutureExt},
+    stream::{FusedStream, FuturesOrdered, Stream, StreamExt},
+    Future,
+    FutureExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    error::Error,
+    fmt::{self, Debug, Display, Formatter},
+    io,
+    pin::Pin,
+    task::Poll,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tower_service::Service;
+
+/// Whether a recorded message was received from or sent to the client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// The message was received from the client.
+    Incoming,
+    /// The message was sent to the client.
+    Outgoing,
+}
+
+/// A single message in a recording.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Record {
+    /// Milliseconds since the Unix epoch at which the message was read or written.
+    pub timestamp: u64,
+    /// Whether the message was received from or sent to the client.
+    pub direction: Direction,
+    /// The JSON-RPC message.
+    pub message: Value,
+}
+
+impl Record {
+    fn new<M: Serialize>(direction: Direction, message: &M) -> Self {
+        let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Record {
+            timestamp: elapsed.as_millis() as u64,
+            direction,
+            message: serde_json::to_value(message).unwrap(),
+        }
+    }
+}
+
+/// Destination of the recording of a [`Server`](crate::Server).
+pub(crate) struct Recorder(Pin<Box<dyn AsyncWrite + Send>>);
+
+impl Recorder {
+    pub(crate) fn new<W: AsyncWrite + Send + 'static>(writer: W) -> Self {
+        Recorder(Box::pin(writer))
+    }
+
+    /// Returns a handle for recording messages, and a future which writes them until all handles
+    /// are dropped.
+    pub(crate) fn start(self) -> (RecordSender, impl Future<Output = ()>) {
+        let (tx, rx) = mpsc::unbounded();
+        let framed = FramedWrite::new(self.0, LanguageServerCodec::new(Framing::LineDelimited));
+        let writer = rx.map(Ok).forward(framed).map(|result| {
+            if let Err(err) = result {
+                log::error!("failed to write recording: {}", err);
+            }
+        });
+        (RecordSender(tx), writer)
+    }
+}
+
+impl Debug for Recorder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_tuple(stringify!(Recorder)).finish_non_exhaustive()
+    }
+}
+
+/// Handle for adding messages to a recording.
+#[derive(Clone, Debug)]
+pub(crate) struct RecordSender(mpsc::UnboundedSender<Record>);
+
+impl RecordSender {
+    pub(crate) fn send<M: Serialize>(&self, direction: Direction, message: &M) {
+        // The writer has stopped after logging an error, so the message can only be dropped.
+        let _ = self.0.unbounded_send(Record::new(direction, message));
+    }
+}
+
+/// Error which stops a [`Replay`] before the end of the recording.
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    /// Failed to read the recording.
+    #[error("failed to read recording: {0}")]
+    Read(io::Error),
+    /// The recording contains a line which is not a valid [`Record`], or an incoming message which
+    /// is not a valid JSON-RPC message.
+    #[error("invalid record: {0}")]
+    Record(Box<dyn Error + Send + Sync>),
+}
+
+/// A recorded outgoing message which differs from the one sent during the replay.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    /// Position of the recorded message among the outgoing messages, starting at 0, or of the sent
+    /// message if nothing was recorded in its place.
+    pub index: usize,
+    /// The recorded message, or `None` if the replay sent more messages than were recorded.
+    pub expected: Option<Value>,
+    /// The message sent during the replay, or `None` if it sent fewer messages than were recorded.
+    pub actual: Option<Value>,
+}
+
+/// Outcome of a [`Replay`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    /// The outgoing messages which differ from the recording, ordered by index.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    /// Returns `true` if the replay sent exactly the recorded messages.
+    pub fn is_match(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl Display for ReplayReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.is_match() {
+            return f.write_str("all outgoing messages match the recording");
+        }
+
+        f.write_str("outgoing messages differ from the recording:")?;
+        for mismatch in &self.mismatches {
+            let show = |message: &Option<Value>| message.as_ref().map_or("nothing".into(), Value::to_string);
+            let (expected, actual) = (show(&mismatch.expected), show(&mismatch.actual));
+            write!(f, "\n#{}: expected {}, got {}", mismatch.index, expected, actual)?;
+        }
+        Ok(())
+    }
+}
+
+/// Driver which feeds a recorded session back into a service and compares its responses.
+///
+/// The incoming messages of the recording are passed to the service in order. Before each of
+/// them, the replay waits until the service has sent as many messages as it had at that point of
+/// the recording, or has nothing left to do. This way, responses to server-to-client requests are
+/// only passed to the service once it has sent the corresponding request.
+///
+/// The outgoing messages are then compared with the recorded ones. Responses are matched with the
+/// recorded response to the same request, since requests handled concurrently may be answered in
+/// a different order. Notifications and requests to the client are compared in order, ignoring
+/// the IDs of the requests, which may legitimately differ between sessions, as do timestamps.
+#[derive(Debug)]
+pub struct Replay<R> {
+    recording: R,
+    timeout: Duration,
+}
+
+impl<R: AsyncRead + Unpin> Replay<R> {
+    /// Creates a new `Replay` of the recording read from `recording`.
+    pub fn new(recording: R) -> Self {
+        Replay {
+            recording,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sets how long to wait for the service to send the recorded messages. The default is 5s.
+    ///
+    /// Once it elapses, the replay carries on with the next incoming message, and the messages
+    /// which were not sent are reported as mismatches.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Replays the recording into `service`, reading the messages it sends from `messages`.
+    ///
+    /// Resolves once every incoming message has been handled, or the service has exited.
+    pub async fn run<T, M>(self, mut service: T, messages: M) -> Result<ReplayReport, ReplayError>
+    where
+        T: Service<Incoming, Response = Option<Outgoing>>,
+        T::Error: Into<Box<dyn Error + Send + Sync>>,
+        M: Stream<Item = Outgoing> + Unpin,
+    {
+        let codec = LanguageServerCodec::<Record>::new(Framing::LineDelimited);
+        let mut records = FramedRead::new(self.recording, codec);
+        let mut output = Output {
+            pending: FuturesOrdered::new(),
+            messages: messages.fuse(),
+            sent: Vec::new(),
+            timeout: self.timeout,
+        };
+        let mut expected = Vec::new();
+
+        while let Some(record) = records.next().await {
+            let record = record.map_err(|err| match into_io_error(err) {
+                Ok(err) => ReplayError::Read(err),
+                Err(err) => ReplayError::Record(err.into()),
+            })?;
+
+            let mut message = match record.direction {
+                Direction::Outgoing => {
+                    expected.push(record.message);
+                    continue;
+                },
+                Direction::Incoming => record.message,
+            };
+
+            output.catch_up(Some(expected.len())).await;
+            if is_response(&message) {
+                if let Some(id) = replayed_id(&expected, &output.sent, &message["id"]) {
+                    message["id"] = id;
+                }
+            }
+
+            let message = serde_json::from_value(message).map_err(|err| ReplayError::Record(err.into()))?;
+            if future::poll_fn(|cx| service.poll_ready(cx)).await.is_err() {
+                break;
+            }
+            output.pending.push_back(service.call(message).unwrap_or_else(|err| {
+                log::error!("{}", err.into());
+                None
+            }));
+        }
+
+        output.catch_up(None).await;
+        Ok(diff(&expected, &output.sent))
+    }
+}
+
+/// Messages sent by the service during a [`Replay`].
+struct Output<F: Future, M> {
+    pending: FuturesOrdered<F>,
+    messages: M,
+    sent: Vec<Value>,
+    timeout: Duration,
+}
+
+impl<F, M> Output<F, M>
+where
+    F: Future<Output = Option<Outgoing>>,
+    M: FusedStream<Item = Outgoing> + Unpin,
+{
+    /// Collects the messages sent by the service until there are `target` of them, or until the
+    /// service is idle if there is no target. Gives up once the timeout has elapsed.
+    async fn catch_up(&mut self, target: Option<usize>) {
+        let deadline = crate::time::sleep(self.timeout);
+        futures::pin_mut!(deadline);
+
+        while target.is_none_or(|target| self.sent.len() < target) {
+            // Resolves to `None` once no handler is pending and no message is queued.
+            let (pending, messages) = (&mut self.pending, &mut self.messages);
+            let next = future::poll_fn(|cx| {
+                if let Poll::Ready(Some(msg)) = messages.poll_next_unpin(cx) {
+                    return Poll::Ready(Some(Some(msg)));
+                }
+                match pending.poll_next_unpin(cx) {
+                    Poll::Ready(Some(response)) => Poll::Ready(Some(response)),
+                    Poll::Ready(None) => Poll::Ready(None),
+                    Poll::Pending => Poll::Pending,
+                }
+            });
+
+            match future::select(next, deadline.as_mut()).await {
+                Either::Left((Some(msg), _)) => self.sent.extend(msg.map(|msg| serde_json::to_value(msg).unwrap())),
+                Either::Left((None, _)) => return,
+                Either::Right(_) => {
+                    log::warn!("service did not catch up with the recording after {:?}", self.timeout);
+                    return;
+                },
+            }
+        }
+    }
+}
+
+fn is_response(message: &Value) -> bool {
+    message.get("method").is_none()
+}
+
+/// Returns the ID of the server-to-client request which was sent during the replay in place of
+/// the recorded request with ID `id`.
+fn replayed_id(expected: &[Value], sent: &[Value], id: &Value) -> Option<Value> {
+    let index = expected
+        .iter()
+        .filter(|msg| !is_response(msg))
+        .position(|msg| msg.get("id") == Some(id))?;
+    let replayed = sent.iter().filter(|msg| !is_response(msg)).nth(index)?;
+    replayed.get("id").cloned()
+}
+
+/// Compares the messages sent during a replay with the recorded ones. Responses are matched by the
+/// ID of the request they answer, the other messages by their position among each other.
+fn diff(expected: &[Value], actual: &[Value]) -> ReplayReport {
+    let without_id = |message: &Value| {
+        let mut message = message.clone();
+        if let Some(fields) = message.as_object_mut() {
+            fields.remove("id");
+        }
+        message
+    };
+
+    let (expected_responses, expected_others): (Vec<_>, Vec<_>) =
+        expected.iter().enumerate().partition(|(_, msg)| is_response(msg));
+    let (mut actual_responses, actual_others): (Vec<_>, Vec<_>) =
+        actual.iter().enumerate().partition(|(_, msg)| is_response(msg));
+
+    let mut mismatches: Vec<_> = (0 .. expected_others.len().max(actual_others.len()))
+        .map(|position| (expected_others.get(position), actual_others.get(position)))
+        .filter(|(expected, actual)| expected.map(|(_, msg)| without_id(msg)) != actual.map(|(_, msg)| without_id(msg)))
+        .map(|(expected, actual)| Mismatch {
+            index: expected.or(actual).map_or(0, |(index, _)| *index),
+            expected: expected.map(|(_, msg)| (*msg).clone()),
+            actual: actual.map(|(_, msg)| (*msg).clone()),
+        })
+        .collect();
+
+    for (index, expected) in expected_responses {
+        let answer = actual_responses.iter().position(|(_, msg)| msg.get("id") == expected.get("id"));
+        let actual = answer.map(|answer| actual_responses.remove(answer).1);
+        if actual != Some(expected) {
+            mismatches.push(Mismatch {
+                index,
+                expected: Some(expected.clone()),
+                actual: actual.cloned(),
+            });
+        }
+    }
+    mismatches.extend(actual_responses.into_iter().map(|(index, actual)| Mismatch {
+        index,
+        expected: None,
+        actual: Some(actual.clone()),
+    }));
+    mismatches.sort_by_key(|mismatch| mismatch.index);
+
+    ReplayReport { mismatches }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jsonrpc, Client, ExitStatus, LspService, Server};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::{
+        sync::{Arc, Mutex},
+        task::Context,
+    };
+
+    #[derive(Debug)]
+    struct Backend {
+        client: Client,
+    }
+
+    #[async_trait]
+    impl crate::LanguageServer for Backend {
+        async fn initialize(&self, _: lsp::InitializeParams) -> jsonrpc::Result<lsp::InitializeResult> {
+            Ok(lsp::InitializeResult::default())
+        }
+
+        async fn initialized(&self, _: lsp::InitializedParams) {
+            let item = lsp::ConfigurationItem {
+                scope_uri: None,
+                section: Some("backend".into()),
+            };
+            let message = match self.client.configuration(vec![item]).await {
+                Ok(values) => values[0].to_string(),
+                Err(err) => err.to_string(),
+            };
+            self.client.log_message(lsp::MessageType::INFO, message).await;
+        }
+
+        async fn shutdown(&self) -> jsonrpc::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn write(&self, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    impl AsyncWrite for SharedBuffer {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.write(buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "runtime-agnostic")]
+    impl AsyncWrite for SharedBuffer {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.write(buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn recording(records: Vec<(Direction, Value)>) -> Vec<u8> {
+        let lines = records.into_iter().map(|(direction, message)| {
+            let record = Record {
+                timestamp: 0,
+                direction,
+                message,
+            };
+            serde_json::to_string(&record).unwrap() + "\n"
+        });
+        lines.collect::<String>().into_bytes()
+    }
+
+    #[tokio::test]
+    async fn records_and_replays_session() {
+        let request = r#"{"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{}},"id":1}"#;
+        let stdin = format!("Content-Length: 3\r\n\r\n{{]}}Content-Length: {}\r\n\r\n{}", request.len(), request);
+        let (mut stdin, mut stdout) = (stdin.as_bytes(), Vec::new());
+
+        let buffer = SharedBuffer::default();
+        let (service, messages) = LspService::new(|client| Backend { client });
+        let status = Server::new(&mut stdin, &mut stdout)
+            .interleave(messages)
+            .record(buffer.clone())
+            .serve(service)
+            .await;
+        assert_eq!(status.unwrap(), ExitStatus::Failure);
+        // The undecodable message is answered, but neither it nor its parse error is recorded.
+        assert!(String::from_utf8(stdout).unwrap().contains(r#""code":-32700"#));
+
+        let recorded = buffer.0.lock().unwrap().clone();
+        let records: Vec<Record> = recorded
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        let directions: Vec<_> = records.iter().map(|record| record.direction).collect();
+        assert_eq!(directions, vec![Direction::Incoming, Direction::Outgoing]);
+        assert!(records.iter().all(|record| record.timestamp > 0));
+        assert_eq!(records[0].message, serde_json::from_str::<Value>(request).unwrap());
+        let response = json!({ "jsonrpc": "2.0", "result": { "capabilities": {} }, "id": 1 });
+        assert_eq!(records[1].message, response);
+
+        let (service, messages) = LspService::new(|client| Backend { client });
+        let report = Replay::new(&recorded[..]).run(service, messages).await.unwrap();
+        assert!(report.is_match(), "{}", report);
+    }
+
+    #[tokio::test]
+    async fn replays_client_requests() {
+        let log = |message: &str| {
+            let params = json!({ "type": 3, "message": message });
+            json!({ "jsonrpc": "2.0", "method": "window/logMessage", "params": params })
+        };
+
+        // The recorded request to the client had a different ID than the one sent when replaying.
+        let params = json!({ "capabilities": { "workspace": { "configuration": true } } });
+        let initialize = json!({ "jsonrpc": "2.0", "method": "initialize", "params": params, "id": 1 });
+        let initialize_result = json!({ "jsonrpc": "2.0", "result": { "capabilities": {} }, "id": 1 });
+        let initialized = json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} });
+        let params = json!({ "items": [{ "section": "backend" }] });
+        let configuration = json!({ "jsonrpc": "2.0", "method": "workspace/configuration", "params": params, "id": 7 });
+        let configuration_result = json!({ "jsonrpc": "2.0", "result": [{ "enabled": true }], "id": 7 });
+        let recorded = recording(vec![
+            (Direction::Incoming, initialize),
+            (Direction::Outgoing, initialize_result),
+            (Direction::Incoming, initialized),
+            (Direction::Outgoing, configuration),
+            (Direction::Incoming, configuration_result),
+            (Direction::Outgoing, log(r#"{"enabled":false}"#)),
+        ]);
+
+        let (service, messages) = LspService::new(|client| Backend { client });
+        let report = Replay::new(&recorded[..]).run(service, messages).await.unwrap();
+        assert_eq!(report.mismatches, vec![Mismatch {
+            index: 2,
+            expected: Some(log(r#"{"enabled":false}"#)),
+            actual: Some(log(r#"{"enabled":true}"#)),
+        }]);
+    }
+
+    #[test]
+    fn diff_matches_responses_by_request() {
+        let response = |id: u64, result: &str| json!({ "jsonrpc": "2.0", "result": result, "id": id });
+        let log = json!({ "jsonrpc": "2.0", "method": "window/logMessage", "params": { "type": 3, "message": "" } });
+
+        let expected = vec![response(1, "a"), log.clone(), response(2, "b")];
+        let reordered = vec![response(2, "b"), log.clone(), response(1, "a")];
+        assert!(diff(&expected, &reordered).is_match());
+
+        let actual = vec![response(3, "c"), log, response(2, "x")];
+        let report = diff(&expected, &actual);
+        let mismatches = vec![
+            Mismatch {
+                index: 0,
+                expected: Some(response(1, "a")),
+                actual: None,
+            },
+            Mismatch {
+                index: 0,
+                expected: None,
+                actual: Some(response(3, "c")),
+            },
+            Mismatch {
+                index: 2,
+                expected: Some(response(2, "b")),
+                actual: Some(response(2, "x")),
+            },
+        ];
+        assert_eq!(report.mismatches, mismatches);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_records() {
+        let (service, messages) = LspService::new(|client| Backend { client });
+        let recorded = b"{\"direction\":\"incoming\"}\n";
+        let result = Replay::new(&recorded[..]).run(service, messages).await;
+        assert!(matches!(result, Err(ReplayError::Record(_))));
+    }
+}
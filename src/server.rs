@@ -1,24 +1,48 @@
 #![allow(dead_code)]
 
+use crate::{jsonrpc::ServerRequests, PositionEncoding};
+use futures::task::AtomicWaker;
+use serde::{de, Deserialize, Deserializer};
 use std::{
     fmt,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        RwLock,
+    },
+    task::Waker,
+    time::Duration,
 };
 
 /// Atomic value which represents the current state of the server.
-pub(crate) struct State(AtomicUsize);
+pub(crate) struct State {
+    kind: AtomicUsize,
+    /// Woken once the server has exited, e.g. by the parent process watcher.
+    exited: AtomicWaker,
+}
 
 impl State {
     pub(crate) const fn new() -> Self {
-        State(AtomicUsize::new(StateKind::Uninitialized as usize))
+        State {
+            kind: AtomicUsize::new(StateKind::Uninitialized as usize),
+            exited: AtomicWaker::new(),
+        }
     }
 
     pub(crate) fn set(&self, state: StateKind) {
-        self.0.store(state as usize, Ordering::SeqCst);
+        self.kind.store(state as usize, Ordering::SeqCst);
+        if state == StateKind::Exited {
+            self.exited.wake();
+        }
+    }
+
+    /// Registers `waker` to be woken once the server moves to the `Exited` state.
+    pub(crate) fn register_exit(&self, waker: &Waker) {
+        self.exited.register(waker);
     }
 
     pub(crate) fn get(&self) -> StateKind {
-        match self.0.load(Ordering::SeqCst) {
+        match self.kind.load(Ordering::SeqCst) {
             0 => StateKind::Uninitialized,
             1 => StateKind::Initializing,
             2 => StateKind::Initialized,
@@ -49,3 +73,239 @@ pub(crate) enum StateKind {
     /// Server received an `exit` notification.
     Exited = 4,
 }
+
+/// Parameters of the `initialize` request.
+///
+/// The `general.positionEncodings` client capability was added in LSP 3.17 and is not known to
+/// `lsp-types` yet, so it is extracted from the raw params before they are deserialized.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct InitializeParams {
+    pub(crate) params: lsp::InitializeParams,
+    pub(crate) position_encodings: Option<Vec<String>>,
+}
+
+impl<'de> Deserialize<'de> for InitializeParams {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let position_encodings = value
+            .pointer("/capabilities/general/positionEncodings")
+            .and_then(|encodings| Vec::deserialize(encodings).ok());
+        let params = lsp::InitializeParams::deserialize(value).map_err(de::Error::custom)?;
+        Ok(InitializeParams {
+            params,
+            position_encodings,
+        })
+    }
+}
+
+#[cfg(test)]
+impl serde::Serialize for InitializeParams {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut value = serde_json::to_value(&self.params).map_err(serde::ser::Error::custom)?;
+        if let Some(encodings) = &self.position_encodings {
+            let general = value["capabilities"]
+                .as_object_mut()
+                .unwrap()
+                .entry("general")
+                .or_insert_with(|| serde_json::json!({}));
+            general["positionEncodings"] = serde_json::json!(encodings);
+        }
+        value.serialize(serializer)
+    }
+}
+
+/// Values negotiated with the client during the `initialize` request.
+#[derive(Debug)]
+pub(crate) struct Session {
+    capabilities: RwLock<Option<lsp::ClientCapabilities>>,
+    trace: RwLock<lsp::TraceOption>,
+    supported_encodings: RwLock<Vec<PositionEncoding>>,
+    position_encoding: RwLock<PositionEncoding>,
+    process_id: RwLock<Option<u32>>,
+    parent_process_poll_interval: RwLock<Option<Duration>>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            capabilities: RwLock::default(),
+            trace: RwLock::default(),
+            supported_encodings: RwLock::new(vec![PositionEncoding::Utf16]),
+            position_encoding: RwLock::default(),
+            process_id: RwLock::default(),
+            parent_process_poll_interval: RwLock::default(),
+        }
+    }
+}
+
+impl Session {
+    pub(crate) fn new() -> Self {
+        Session::default()
+    }
+
+    /// Records the values sent by the client in its `initialize` request.
+    ///
+    /// The position encoding is the first of the encodings supported by the server which the
+    /// client also advertised, falling back to UTF-16, which all clients must support.
+    pub(crate) fn initialize(&self, params: &InitializeParams) {
+        *self.capabilities.write().unwrap() = Some(params.params.capabilities.clone());
+        *self.trace.write().unwrap() = params.params.trace.unwrap_or_default();
+        *self.process_id.write().unwrap() = params.params.process_id;
+
+        let offered = params.position_encodings.as_deref().unwrap_or_default();
+        let encoding = self
+            .supported_encodings
+            .read()
+            .unwrap()
+            .iter()
+            .copied()
+            .find(|encoding| offered.iter().any(|name| name == encoding.as_str()))
+            .unwrap_or_default();
+        *self.position_encoding.write().unwrap() = encoding;
+    }
+
+    /// Forgets all negotiated values, e.g. after the `initialize` request failed.
+    pub(crate) fn reset(&self) {
+        *self.capabilities.write().unwrap() = None;
+        *self.trace.write().unwrap() = lsp::TraceOption::Off;
+        *self.position_encoding.write().unwrap() = PositionEncoding::Utf16;
+        *self.process_id.write().unwrap() = None;
+    }
+
+    /// Enables watching the parent process every `interval` once the server is initialized.
+    pub(crate) fn set_watch_parent_process(&self, interval: Duration) {
+        *self.parent_process_poll_interval.write().unwrap() = Some(interval);
+    }
+
+    /// Returns the ID of the parent process to watch and the interval at which to check it, if
+    /// enabled and sent by the client.
+    pub(crate) fn parent_process_to_watch(&self) -> Option<(u32, Duration)> {
+        let interval = (*self.parent_process_poll_interval.read().unwrap())?;
+        Some(((*self.process_id.read().unwrap())?, interval))
+    }
+
+    /// Sets the position encodings supported by the server, in order of preference.
+    pub(crate) fn set_supported_encodings(&self, encodings: Vec<PositionEncoding>) {
+        *self.supported_encodings.write().unwrap() = encodings;
+    }
+
+    /// Returns the position encoding negotiated with the client.
+    pub(crate) fn position_encoding(&self) -> PositionEncoding {
+        *self.position_encoding.read().unwrap()
+    }
+
+    /// Returns the capabilities advertised by the client, if it has been initialized.
+    pub(crate) fn capabilities(&self) -> Option<lsp::ClientCapabilities> {
+        self.capabilities.read().unwrap().clone()
+    }
+
+    /// Returns the current trace level, as set by `initialize` or the last `$/setTrace`.
+    pub(crate) fn trace(&self) -> lsp::TraceOption {
+        *self.trace.read().unwrap()
+    }
+
+    /// Updates the trace level in response to a `$/setTrace` notification.
+    pub(crate) fn set_trace(&self, value: lsp::TraceOption) {
+        *self.trace.write().unwrap() = value;
+    }
+
+    /// Returns `true` if the client has been initialized and `check` reports support for a feature.
+    pub(crate) fn supports(&self, check: fn(&lsp::ClientCapabilities) -> Option<bool>) -> bool {
+        let capabilities = self.capabilities.read().unwrap();
+        capabilities.as_ref().and_then(check).unwrap_or(false)
+    }
+
+    /// Returns `true` if the client supports server-initiated work done progress.
+    pub(crate) fn supports_work_done_progress(&self) -> bool {
+        self.supports(|c| c.window.as_ref()?.work_done_progress)
+    }
+}
+
+/// Watches the process with the given ID on a background thread, and moves the server to the
+/// `Exited` state once it is gone, cancelling all pending requests.
+///
+/// The thread stops once the server has exited for any reason.
+#[cfg(target_os = "linux")]
+pub(crate) fn watch_parent_process(pid: u32, interval: Duration, state: Arc<State>, pending: ServerRequests) {
+    let watcher = move || loop {
+        std::thread::sleep(interval);
+        if state.get() == StateKind::Exited {
+            return;
+        }
+        if !process_is_alive(pid) {
+            log::warn!("parent process {} is gone, exiting", pid);
+            state.set(StateKind::Exited);
+            pending.cancel_all();
+            return;
+        }
+    };
+
+    let spawned = std::thread::Builder::new()
+        .name("lspower-parent-watch".into())
+        .spawn(watcher);
+    if let Err(err) = spawned {
+        log::error!("failed to spawn thread watching parent process {}: {}", pid, err);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn watch_parent_process(pid: u32, _: Duration, _: Arc<State>, _: ServerRequests) {
+    log::warn!("watching parent process {} is only supported on Linux, ignoring", pid);
+}
+
+/// Returns `false` if the process with the given ID does not exist or is a zombie.
+#[cfg(target_os = "linux")]
+fn process_is_alive(pid: u32) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // The state follows the executable name, which is in parentheses and may contain spaces.
+        Ok(stat) => {
+            let state = stat.rsplit(')').next().and_then(|rest| rest.split_whitespace().next());
+            !matches!(state, Some("Z") | Some("X"))
+        },
+        Err(_) => false,
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::jsonrpc::{Error, Id, Response};
+
+    #[test]
+    fn process_liveness() {
+        assert!(process_is_alive(std::process::id()));
+
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        assert!(process_is_alive(child.id()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!process_is_alive(child.id()));
+    }
+
+    #[tokio::test]
+    async fn exits_when_parent_process_is_gone() {
+        let state = Arc::new(State::new());
+        state.set(StateKind::Initialized);
+        let pending = ServerRequests::new();
+        let handler = futures::future::pending::<crate::jsonrpc::Result<()>>();
+        let request = tokio::spawn(pending.execute(Id::Number(1), "foo", handler));
+
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        watch_parent_process(child.id(), Duration::from_millis(10), state.clone(), pending);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(state.get(), StateKind::Initialized);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let response = request.await.unwrap();
+        let cancelled = Response::error(Some(Id::Number(1)), Error::request_cancelled());
+        assert_eq!(response, cancelled);
+        assert_eq!(state.get(), StateKind::Exited);
+    }
+}
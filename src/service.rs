@@ -1,5 +1,8 @@
 //! Service abstraction for language servers.
 
+mod custom;
+
+pub(crate) use self::custom::CustomMethods;
 use futures::{
     channel::mpsc,
     future,
@@ -29,6 +32,36 @@ impl Display for ExitedError {
 impl Error for ExitedError {
 }
 
+/// Status with which the language server process should exit, as required by the specification.
+///
+/// [`Server::serve`] resolves to this status once the connection is closed, unless reading or
+/// writing failed. The specification requires the server to exit with code 0 if it received a
+/// `shutdown` request before the `exit` notification, and with code 1 otherwise:
+///
+/// ```rust,ignore
+/// let status = Server::new(stdin, stdout).interleave(messages).serve(service).await;
+/// std::process::exit(status.map_or(1, |status| status.code()));
+/// ```
+///
+/// [`Server::serve`]: crate::Server::serve
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ExitStatus {
+    /// The client sent `shutdown` before `exit`.
+    Success,
+    /// The client sent `exit` without `shutdown`, or the connection closed before `exit`.
+    Failure,
+}
+
+impl ExitStatus {
+    /// Returns the process exit code for this status, i.e. 0 for success and 1 for failure.
+    pub fn code(&self) -> i32 {
+        match *self {
+            ExitStatus::Success => 0,
+            ExitStatus::Failure => 1,
+        }
+    }
+}
+
 /// Stream of messages produced by the language server.
 #[derive(Debug)]
 #[must_use = "streams do nothing unless polled"]
@@ -70,32 +103,247 @@ pub struct LspService {
     server: Arc<dyn crate::LanguageServer>,
     pending_server: crate::jsonrpc::ServerRequests,
     pending_client: Arc<crate::jsonrpc::ClientRequests>,
+    custom: CustomMethods,
+    documents: Option<crate::documents::DocumentStore>,
+    metrics: crate::metrics::Metrics,
     state: Arc<crate::server::State>,
+    session: Arc<crate::server::Session>,
 }
 
 impl LspService {
     /// Creates a new `LspService` with the given server backend, also returning a stream of
     /// notifications from the server back to the client.
     pub fn new<T, F>(init: F) -> (Self, MessageStream)
+    where
+        F: FnOnce(crate::client::Client) -> T,
+        T: crate::LanguageServer,
+    {
+        LspService::build(init).finish()
+    }
+
+    /// Returns a handle to the request metrics of this service.
+    ///
+    /// See the [`metrics`](crate::metrics) module for how to report them to the client.
+    pub fn metrics(&self) -> crate::metrics::Metrics {
+        self.metrics.clone()
+    }
+
+    /// Starts building a new `LspService` with the given server backend.
+    ///
+    /// Unlike [`LspService::new`], the returned [`LspServiceBuilder`] allows typed handlers for
+    /// custom requests and notifications to be registered before the service is created.
+    pub fn build<T, F>(init: F) -> LspServiceBuilder<T>
     where
         F: FnOnce(crate::client::Client) -> T,
         T: crate::LanguageServer,
     {
         let state = Arc::new(crate::server::State::new());
+        let session = Arc::new(crate::server::Session::new());
         let (tx, rx) = mpsc::channel(1);
         let messages = MessageStream(rx);
 
         let pending_client = Arc::new(crate::jsonrpc::ClientRequests::new());
-        let client = crate::client::Client::new(tx, pending_client.clone(), state.clone());
+        let client = crate::client::Client::new(tx, pending_client.clone(), state.clone(), session.clone());
+        let pending_server = crate::jsonrpc::ServerRequests::new();
+        let metrics = crate::metrics::Metrics::new(pending_server.clone(), pending_client.clone());
 
-        let service = LspService {
-            server: Arc::from(init(client)),
-            pending_server: crate::jsonrpc::ServerRequests::new(),
+        LspServiceBuilder {
+            server: Arc::new(init(client)),
+            pending_server,
             pending_client,
+            custom: CustomMethods::default(),
+            documents: None,
+            metrics,
             state,
+            session,
+            messages,
+        }
+    }
+}
+
+/// Builder for [`LspService`] which allows registering custom JSON-RPC methods.
+///
+/// Handlers registered here take precedence over [`LanguageServer::request_else`] and
+/// [`LanguageServer::notification_else`]. Method names already handled by the
+/// [`LanguageServer`] trait are always routed to the trait and cannot be overridden.
+///
+/// [`LanguageServer`]: crate::LanguageServer
+/// [`LanguageServer::request_else`]: crate::LanguageServer::request_else
+/// [`LanguageServer::notification_else`]: crate::LanguageServer::notification_else
+pub struct LspServiceBuilder<T> {
+    server: Arc<T>,
+    pending_server: crate::jsonrpc::ServerRequests,
+    pending_client: Arc<crate::jsonrpc::ClientRequests>,
+    custom: CustomMethods,
+    documents: Option<crate::documents::DocumentStore>,
+    metrics: crate::metrics::Metrics,
+    state: Arc<crate::server::State>,
+    session: Arc<crate::server::Session>,
+    messages: MessageStream,
+}
+
+impl<T: crate::LanguageServer> LspServiceBuilder<T> {
+    /// Registers a typed handler for the custom request `R`.
+    ///
+    /// The request parameters are deserialized into `R::Params` before the handler is called. If
+    /// this fails, the client receives an "invalid params" error. Pending handlers can be
+    /// cancelled by the client with [`$/cancelRequest`] like any other request.
+    ///
+    /// [`$/cancelRequest`]: https://microsoft.github.io/language-server-protocol/specification#cancelRequest
+    ///
+    /// # Panics
+    ///
+    /// Panics if a handler for `R::METHOD` has already been registered.
+    pub fn custom_method<R, F, Fut>(mut self, handler: F) -> Self
+    where
+        R: lsp::request::Request,
+        F: Fn(Arc<T>, R::Params) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = crate::jsonrpc::Result<R::Result>> + Send + 'static,
+    {
+        let server = self.server.clone();
+        self.custom.insert_request::<R, _, _>(move |params| handler(server.clone(), params));
+        self
+    }
+
+    /// Registers a typed handler for the custom notification `N`.
+    ///
+    /// The notification parameters are deserialized into `N::Params` before the handler is
+    /// called. If this fails, the notification is logged and dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a handler for `N::METHOD` has already been registered.
+    pub fn custom_notification<N, F, Fut>(mut self, handler: F) -> Self
+    where
+        N: lsp::notification::Notification,
+        F: Fn(Arc<T>, N::Params) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let server = self.server.clone();
+        self.custom.insert_notification::<N, _, _>(move |params| handler(server.clone(), params));
+        self
+    }
+
+    /// Answers pending `R` requests with a "content modified" error when the document they refer
+    /// to changes.
+    ///
+    /// When a `textDocument/didChange` notification arrives, every pending request for `R` whose
+    /// params have a `textDocument.uri` equal to the changed document is cancelled, and the client
+    /// receives [`Error::content_modified`] instead of a stale result. The specification suggests
+    /// this for requests such as [`SemanticTokensFullRequest`] and [`CodeLensRequest`].
+    ///
+    /// Only requests routed to the [`LanguageServer`] trait are tracked.
+    ///
+    /// [`Error::content_modified`]: crate::jsonrpc::Error::content_modified
+    /// [`SemanticTokensFullRequest`]: lsp::request::SemanticTokensFullRequest
+    /// [`CodeLensRequest`]: lsp::request::CodeLensRequest
+    /// [`LanguageServer`]: crate::LanguageServer
+    pub fn invalidate_on_change<R: lsp::request::Request>(mut self) -> Self {
+        self.pending_server.invalidate_on_change(R::METHOD);
+        self
+    }
+
+    /// Aborts `R` request handlers which are still running after `timeout`.
+    ///
+    /// The client receives a "request failed" error naming the method and the timeout instead of
+    /// a result, and the handler no longer occupies one of the concurrently processed messages of
+    /// [`Server::serve`]. Handlers are aborted even if they asked for their cancellation token.
+    ///
+    /// This applies to custom requests as well. By default, handlers may run forever.
+    ///
+    /// [`Server::serve`]: crate::Server::serve
+    pub fn request_timeout<R: lsp::request::Request>(mut self, timeout: std::time::Duration) -> Self {
+        self.pending_server.set_timeout(R::METHOD, timeout);
+        self
+    }
+
+    /// Sets the time given to request handlers which asked for their [`CancellationToken`] to
+    /// return once it is signalled, before they are aborted. The default is one second.
+    ///
+    /// [`CancellationToken`]: crate::CancellationToken
+    pub fn cancellation_grace_period(mut self, grace_period: std::time::Duration) -> Self {
+        self.pending_server.set_grace_period(grace_period);
+        self
+    }
+
+    /// Keeps `store` in sync with the documents opened by the client.
+    ///
+    /// The `textDocument/didOpen`, `textDocument/didChange` and `textDocument/didClose`
+    /// notifications are applied to the store before the corresponding [`LanguageServer`] handler
+    /// runs. Keep a clone of the store in the language server to read documents from its handlers.
+    ///
+    /// [`LanguageServer`]: crate::LanguageServer
+    pub fn document_store(mut self, store: crate::documents::DocumentStore) -> Self {
+        self.documents = Some(store);
+        self
+    }
+
+    /// Sets the position encodings supported by the server, in order of preference.
+    ///
+    /// During initialization, the first of these encodings which the client also advertised in its
+    /// `general.positionEncodings` capability is selected and reported back to the client in the
+    /// `positionEncoding` server capability. If there is none, UTF-16 is used, which all clients
+    /// support. The selected encoding is available from [`Client::position_encoding`] and is used
+    /// to apply changes to the [`document_store`](Self::document_store).
+    ///
+    /// The default is to only support [`PositionEncoding::Utf16`].
+    ///
+    /// [`Client::position_encoding`]: crate::Client::position_encoding
+    /// [`PositionEncoding::Utf16`]: crate::PositionEncoding::Utf16
+    pub fn position_encodings(self, encodings: impl IntoIterator<Item = crate::PositionEncoding>) -> Self {
+        self.session.set_supported_encodings(encodings.into_iter().collect());
+        self
+    }
+
+    /// Exits the language server when the editor process which started it disappears.
+    ///
+    /// After a successful `initialize` request carrying a `processId`, that process is checked
+    /// every `interval`, e.g. every few seconds. Once it is gone, the service behaves as if it
+    /// received the `exit` notification: pending requests are cancelled and [`Server::serve`]
+    /// resolves to [`ExitStatus::Failure`] without waiting for another message, even if the
+    /// connection is still open. This prevents orphaned servers from piling up when the editor
+    /// crashes without closing the connection.
+    ///
+    /// This is only supported on Linux and does nothing on other platforms.
+    ///
+    /// [`Server::serve`]: crate::Server::serve
+    pub fn watch_parent_process(self, interval: std::time::Duration) -> Self {
+        self.session.set_watch_parent_process(interval);
+        self
+    }
+
+    /// Returns a handle to the request metrics of the service being built.
+    ///
+    /// This allows custom handlers registered on this builder to report the metrics, e.g. in
+    /// response to a `$/myServer/stats` request.
+    pub fn metrics(&self) -> crate::metrics::Metrics {
+        self.metrics.clone()
+    }
+
+    /// Creates the `LspService`, also returning a stream of notifications from the server back to
+    /// the client.
+    pub fn finish(self) -> (LspService, MessageStream) {
+        let service = LspService {
+            server: self.server,
+            pending_server: self.pending_server,
+            pending_client: self.pending_client,
+            custom: self.custom,
+            documents: self.documents,
+            metrics: self.metrics,
+            state: self.state,
+            session: self.session,
         };
 
-        (service, messages)
+        (service, self.messages)
+    }
+}
+
+impl<T> Debug for LspServiceBuilder<T> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(LspServiceBuilder))
+            .field("custom", &self.custom)
+            .field("state", &self.state)
+            .finish()
     }
 }
 
@@ -104,7 +352,9 @@ impl Service<crate::jsonrpc::Incoming> for LspService {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
     type Response = Option<crate::jsonrpc::Outgoing>;
 
-    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        // Lets `Server::serve` stop waiting for the next message if the server exits meanwhile.
+        self.state.register_exit(cx.waker());
         if self.state.get() == crate::server::StateKind::Exited {
             Poll::Ready(Err(ExitedError))
         } else {
@@ -118,7 +368,31 @@ impl Service<crate::jsonrpc::Incoming> for LspService {
         } else {
             match request {
                 crate::jsonrpc::Incoming::Request(req) => {
-                    super::generated_impl::handle_request(self.server.clone(), &self.state, &self.pending_server, req)
+                    // Methods are only told apart if they have a handler, so that clients cannot
+                    // make the metrics grow without limit by sending arbitrary method names.
+                    let method = req.method();
+                    let known = super::generated_impl::METHODS.contains(&method) || self.custom.contains(method);
+                    let method = if known { method } else { crate::metrics::UNKNOWN_METHOD }.to_owned();
+                    let measurement = self.metrics.start(method);
+                    super::generated_impl::handle_request(
+                        self.server.clone(),
+                        &self.state,
+                        &self.session,
+                        &self.pending_server,
+                        &self.custom,
+                        self.documents.as_ref(),
+                        *req,
+                    )
+                    .map(move |result| {
+                        let error = match result {
+                            Ok(Some(crate::jsonrpc::Outgoing::Response(ref res))) => res.error_code(),
+                            Ok(_) => None,
+                            Err(_) => Some(crate::jsonrpc::ErrorCode::RequestCancelled),
+                        };
+                        measurement.finish(error);
+                        result
+                    })
+                    .boxed()
                 },
                 crate::jsonrpc::Incoming::Response(res) => {
                     log::trace!("received client response: {:?}", res);
@@ -135,6 +409,9 @@ impl Debug for LspService {
         f.debug_struct(stringify!(LspService))
             .field("pending_server", &self.pending_server)
             .field("pending_client", &self.pending_client)
+            .field("custom", &self.custom)
+            .field("documents", &self.documents)
+            .field("metrics", &self.metrics)
             .field("state", &self.state)
             .finish()
     }
@@ -171,7 +448,7 @@ mod tests {
     async fn call_response() {
         use crate::jsonrpc::{Id, Incoming, Response};
 
-        let (service, _) = LspService::new(|_| Mock::default());
+        let (service, _) = LspService::new(|_| Mock);
         let mut service = Spawn::new(service);
 
         let initialize: crate::jsonrpc::Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
@@ -186,13 +463,13 @@ mod tests {
 
     #[test]
     fn debug() {
-        let (service, _) = LspService::new(|_| Mock::default());
-        format!("{:?}", service);
+        let (service, _) = LspService::new(|_| Mock);
+        let _ = format!("{:?}", service);
     }
 
     #[tokio::test]
     async fn initializes_only_once() {
-        let (service, _) = LspService::new(|_| Mock::default());
+        let (service, _) = LspService::new(|_| Mock);
         let mut service = Spawn::new(service);
 
         let initialize: crate::jsonrpc::Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
@@ -209,7 +486,7 @@ mod tests {
 
     #[tokio::test]
     async fn refuses_requests_after_shutdown() {
-        let (service, _) = LspService::new(|_| Mock::default());
+        let (service, _) = LspService::new(|_| Mock);
         let mut service = Spawn::new(service);
 
         let initialize: crate::jsonrpc::Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
@@ -232,7 +509,7 @@ mod tests {
 
     #[tokio::test]
     async fn exit_notification() {
-        let (service, _) = LspService::new(|_| Mock::default());
+        let (service, _) = LspService::new(|_| Mock);
         let mut service = Spawn::new(service);
 
         let initialized: crate::jsonrpc::Incoming = serde_json::from_str(INITIALIZED_NOTIF).unwrap();
@@ -247,6 +524,545 @@ mod tests {
         assert_eq!(service.call(initialized).await, Err(ExitedError));
     }
 
+    #[tokio::test]
+    async fn set_trace() {
+        let (service, _) = LspService::new(|_| Mock);
+        let mut service = Spawn::new(service);
+
+        let raw = json!({
+            "jsonrpc": "2.0",
+            "method": "initialize",
+            "params": { "capabilities": {}, "trace": "messages" },
+            "id": 1,
+        });
+        let initialize: crate::jsonrpc::Incoming = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert!(service.call(initialize).await.is_ok());
+        assert_eq!(service.get_ref().session.trace(), lsp::TraceOption::Messages);
+
+        let raw = json!({ "jsonrpc": "2.0", "method": "$/setTrace", "params": { "value": "verbose" } });
+        let set_trace: crate::jsonrpc::Incoming = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert_eq!(service.call(set_trace).await, Ok(None));
+        assert_eq!(service.get_ref().session.trace(), lsp::TraceOption::Verbose);
+
+        let raw = json!({ "jsonrpc": "2.0", "method": "$/setTrace", "params": { "value": "bogus" } });
+        let set_trace: crate::jsonrpc::Incoming = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert_eq!(service.call(set_trace).await, Ok(None));
+        assert_eq!(service.get_ref().session.trace(), lsp::TraceOption::Verbose);
+    }
+
+    #[tokio::test]
+    async fn position_encoding() {
+        use crate::{
+            jsonrpc::Outgoing,
+            PositionEncoding::{Utf16, Utf32, Utf8},
+        };
+
+        let cases = vec![
+            (None, Utf16, None),
+            (Some(json!(["utf-16", "utf-8"])), Utf32, None),
+            (Some(json!(["utf-16", "utf-8"])), Utf8, Some("utf-8")),
+            (Some(json!(["utf-32", "utf-16"])), Utf32, Some("utf-32")),
+        ];
+        for (offered, preferred, expected) in cases {
+            let (service, _) = LspService::build(|_| Mock)
+                .position_encodings(vec![preferred, Utf16])
+                .finish();
+            let mut service = Spawn::new(service);
+
+            let mut params = json!({ "capabilities": {} });
+            if let Some(offered) = offered {
+                params["capabilities"]["general"] = json!({ "positionEncodings": offered });
+            }
+            let raw = json!({ "jsonrpc": "2.0", "method": "initialize", "params": params, "id": 1 });
+            let initialize: crate::jsonrpc::Incoming = serde_json::from_value(raw).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            let response = match service.call(initialize).await {
+                Ok(Some(Outgoing::Response(response))) => serde_json::to_value(response).unwrap(),
+                other => panic!("unexpected response: {:?}", other),
+            };
+
+            let negotiated = response.pointer("/result/capabilities/positionEncoding");
+            assert_eq!(negotiated.and_then(|v| v.as_str()), expected);
+            let encoding = service.get_ref().session.position_encoding();
+            assert_eq!(encoding.as_str(), expected.unwrap_or("utf-16"));
+        }
+    }
+
+    #[tokio::test]
+    async fn document_store() {
+        use crate::{documents::DocumentStore, jsonrpc::Incoming};
+        use std::sync::Mutex;
+
+        #[derive(Debug)]
+        struct Editor {
+            documents: DocumentStore,
+            seen: Mutex<Vec<String>>,
+        }
+
+        #[async_trait]
+        impl crate::LanguageServer for Editor {
+            async fn initialize(&self, _: lsp::InitializeParams) -> crate::jsonrpc::Result<lsp::InitializeResult> {
+                Ok(lsp::InitializeResult::default())
+            }
+
+            async fn shutdown(&self) -> crate::jsonrpc::Result<()> {
+                Ok(())
+            }
+
+            async fn did_change(&self, params: lsp::DidChangeTextDocumentParams) {
+                let document = self.documents.get(&params.text_document.uri).unwrap();
+                self.seen.lock().unwrap().push(document.text().into());
+            }
+        }
+
+        let documents = DocumentStore::new();
+        let editor = Arc::new(Editor {
+            documents: documents.clone(),
+            seen: Mutex::default(),
+        });
+        let (service, _) = LspService::build(|_| editor.clone())
+            .document_store(documents.clone())
+            .finish();
+        let mut service = Spawn::new(service);
+
+        let initialize: Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert!(service.call(initialize).await.is_ok());
+
+        let uri = lsp::Url::parse("inmemory::///test").unwrap();
+        let notifications = vec![
+            json!({
+                "method": "textDocument/didOpen",
+                "params": { "textDocument": { "uri": uri, "languageId": "rust", "version": 1, "text": "let x = 1;" } },
+            }),
+            json!({
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": { "uri": uri, "version": 2 },
+                    "contentChanges": [{
+                        "range": { "start": { "line": 0, "character": 4 }, "end": { "line": 0, "character": 5 } },
+                        "text": "answer",
+                    }],
+                },
+            }),
+        ];
+        for mut notification in notifications {
+            notification["jsonrpc"] = json!("2.0");
+            let notification: Incoming = serde_json::from_value(notification).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(notification).await, Ok(None));
+        }
+
+        assert_eq!(*editor.seen.lock().unwrap(), vec!["let answer = 1;".to_string()]);
+        assert_eq!(documents.get(&uri).unwrap().version(), 2);
+
+        let params = json!({ "textDocument": { "uri": uri } });
+        let raw = json!({ "jsonrpc": "2.0", "method": "textDocument/didClose", "params": params });
+        let did_close: Incoming = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert_eq!(service.call(did_close).await, Ok(None));
+        assert_eq!(documents.get(&uri), None);
+    }
+
+    #[tokio::test]
+    async fn invalidate_on_change() {
+        use crate::jsonrpc::{Error, Id, Incoming, Outgoing, Response};
+
+        #[derive(Debug)]
+        struct Slow;
+
+        #[async_trait]
+        impl crate::LanguageServer for Slow {
+            async fn initialize(&self, _: lsp::InitializeParams) -> crate::jsonrpc::Result<lsp::InitializeResult> {
+                Ok(lsp::InitializeResult::default())
+            }
+
+            async fn shutdown(&self) -> crate::jsonrpc::Result<()> {
+                Ok(())
+            }
+
+            async fn code_lens(&self, _: lsp::CodeLensParams) -> crate::jsonrpc::Result<Option<Vec<lsp::CodeLens>>> {
+                tokio::time::sleep(std::time::Duration::from_secs(50)).await;
+                Ok(None)
+            }
+        }
+
+        let (service, _) = LspService::build(|_| Slow)
+            .invalidate_on_change::<lsp::request::CodeLensRequest>()
+            .finish();
+        let mut service = Spawn::new(service);
+
+        let initialize: Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert!(service.call(initialize).await.is_ok());
+
+        let uri = "inmemory::///test";
+        let raw = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/codeLens",
+            "params": { "textDocument": { "uri": uri } },
+            "id": 2,
+        });
+        let code_lens: Incoming = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        let response = tokio::spawn(service.call(code_lens));
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let raw = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": { "textDocument": { "uri": uri, "version": 2 }, "contentChanges": [] },
+        });
+        let did_change: Incoming = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert_eq!(service.call(did_change).await, Ok(None));
+
+        let expected = Response::error(Some(Id::Number(2)), Error::content_modified());
+        let response = response.await.expect("task panicked");
+        assert_eq!(response, Ok(Some(Outgoing::Response(expected))));
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        use crate::jsonrpc::{Error, Id, Incoming, Outgoing, Response};
+
+        #[derive(Debug)]
+        struct Hung;
+
+        #[async_trait]
+        impl crate::LanguageServer for Hung {
+            async fn initialize(&self, _: lsp::InitializeParams) -> crate::jsonrpc::Result<lsp::InitializeResult> {
+                Ok(lsp::InitializeResult::default())
+            }
+
+            async fn shutdown(&self) -> crate::jsonrpc::Result<()> {
+                Ok(())
+            }
+
+            async fn completion(
+                &self,
+                _: lsp::CompletionParams,
+            ) -> crate::jsonrpc::Result<Option<lsp::CompletionResponse>> {
+                futures::future::pending().await
+            }
+        }
+
+        let timeout = std::time::Duration::from_millis(30);
+        let (service, _) = LspService::build(|_| Hung)
+            .request_timeout::<lsp::request::Completion>(timeout)
+            .finish();
+        let pending = service.pending_server.clone();
+        let mut service = Spawn::new(service);
+
+        let initialize: Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert!(service.call(initialize).await.is_ok());
+
+        let raw = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/completion",
+            "params": { "textDocument": { "uri": "inmemory::///test" }, "position": { "line": 0, "character": 0 } },
+            "id": 2,
+        });
+        let completion: Incoming = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        let response = service.call(completion).await;
+
+        let error = Error::request_failed("Request \"textDocument/completion\" timed out after 30ms");
+        let expected = Response::error(Some(Id::Number(2)), error);
+        assert_eq!(response, Ok(Some(Outgoing::Response(expected))));
+        assert_eq!(format!("{:?}", pending), "{}");
+    }
+
+    #[tokio::test]
+    async fn cancellation_grace_period() {
+        use crate::jsonrpc::{Error, Id, Incoming, Outgoing, Response};
+
+        #[derive(Debug)]
+        struct Stubborn;
+
+        #[async_trait]
+        impl crate::LanguageServer for Stubborn {
+            async fn initialize(&self, _: lsp::InitializeParams) -> crate::jsonrpc::Result<lsp::InitializeResult> {
+                Ok(lsp::InitializeResult::default())
+            }
+
+            async fn shutdown(&self) -> crate::jsonrpc::Result<()> {
+                Ok(())
+            }
+
+            async fn completion(
+                &self,
+                _: lsp::CompletionParams,
+            ) -> crate::jsonrpc::Result<Option<lsp::CompletionResponse>> {
+                let _token = crate::CancellationToken::current().unwrap();
+                futures::future::pending().await
+            }
+        }
+
+        // The handler asks for its token but ignores it, so it is aborted once the grace period,
+        // much shorter than the default, has elapsed.
+        let (service, _) = LspService::build(|_| Stubborn)
+            .request_timeout::<lsp::request::Completion>(std::time::Duration::from_millis(30))
+            .cancellation_grace_period(std::time::Duration::from_millis(50))
+            .finish();
+        let mut service = Spawn::new(service);
+
+        let initialize: Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert!(service.call(initialize).await.is_ok());
+
+        let raw = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/completion",
+            "params": { "textDocument": { "uri": "inmemory::///test" }, "position": { "line": 0, "character": 0 } },
+            "id": 2,
+        });
+        let completion: Incoming = serde_json::from_value(raw).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        let response = tokio::time::timeout(std::time::Duration::from_millis(500), service.call(completion)).await;
+
+        let error = Error::request_failed("Request \"textDocument/completion\" timed out after 30ms");
+        let expected = Response::error(Some(Id::Number(2)), error);
+        assert_eq!(response.expect("handler was not aborted"), Ok(Some(Outgoing::Response(expected))));
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        use crate::{
+            jsonrpc::{Incoming, Outgoing},
+            metrics::MetricsSnapshot,
+        };
+
+        enum Stats {}
+
+        impl lsp::request::Request for Stats {
+            type Params = ();
+            type Result = MetricsSnapshot;
+
+            const METHOD: &'static str = "$/myServer/stats";
+        }
+
+        let builder = LspService::build(|_| Mock);
+        let metrics = builder.metrics();
+        let (service, _) = builder
+            .custom_method::<Stats, _, _>(move |_, ()| {
+                let metrics = metrics.clone();
+                async move { Ok(metrics.snapshot()) }
+            })
+            .finish();
+        let metrics = service.metrics();
+        let mut service = Spawn::new(service);
+
+        let initialize: Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
+        assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+        assert!(service.call(initialize).await.is_ok());
+        let initialized: Incoming = serde_json::from_str(INITIALIZED_NOTIF).unwrap();
+        assert_eq!(service.call(initialized).await, Ok(None));
+
+        let raw = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/hover",
+            "params": { "textDocument": { "uri": "inmemory::///test" }, "position": { "line": 0, "character": 0 } },
+            "id": 2,
+        });
+        assert!(service.call(serde_json::from_value(raw.clone()).unwrap()).await.is_ok());
+
+        // A request whose future is dropped before it completes counts as cancelled.
+        drop(service.call(serde_json::from_value(raw).unwrap()));
+
+        for (i, method) in ["$/unknown/1", "$/unknown/2"].iter().enumerate() {
+            let raw = json!({ "jsonrpc": "2.0", "method": method, "id": 10 + i });
+            assert!(service.call(serde_json::from_value(raw).unwrap()).await.is_ok());
+        }
+
+        let stats = json!({ "jsonrpc": "2.0", "method": "$/myServer/stats", "id": 3 });
+        let response = match service.call(serde_json::from_value(stats).unwrap()).await {
+            Ok(Some(Outgoing::Response(response))) => response,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let reported: MetricsSnapshot = serde_json::from_value(response.into_parts().1.unwrap()).unwrap();
+        assert_eq!(reported.pending_server_requests, 1);
+        assert_eq!(reported.pending_client_requests, 0);
+        assert!(!reported.methods.contains_key("$/myServer/stats"));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.pending_server_requests, 0);
+        let counts: Vec<_> = snapshot
+            .methods
+            .iter()
+            .map(|(method, metrics)| (method.as_str(), metrics.count, metrics.errors, metrics.cancellations))
+            .collect();
+        assert_eq!(counts, vec![
+            ("$/myServer/stats", 1, 0, 0),
+            ("<unknown>", 2, 2, 0),
+            ("initialize", 1, 0, 0),
+            ("initialized", 1, 0, 0),
+            ("textDocument/hover", 2, 1, 1),
+        ]);
+        let hover = &snapshot.methods["textDocument/hover"].latency;
+        assert_eq!(hover.counts.iter().sum::<u64>(), 2);
+    }
+
+    mod custom_method {
+        use super::*;
+        use crate::jsonrpc::{Error, Id, Incoming, Outgoing, Response};
+        use serde::{Deserialize, Serialize};
+        use std::{sync::Mutex, time::Duration};
+
+        #[derive(Debug, Deserialize, Serialize)]
+        struct CustomParams {
+            value: u64,
+        }
+
+        enum CustomRequest {}
+
+        impl lsp::request::Request for CustomRequest {
+            type Params = CustomParams;
+            type Result = u64;
+
+            const METHOD: &'static str = "custom/request";
+        }
+
+        enum CustomNotification {}
+
+        impl lsp::notification::Notification for CustomNotification {
+            type Params = CustomParams;
+
+            const METHOD: &'static str = "custom/notification";
+        }
+
+        #[derive(Debug, Default)]
+        struct Backend(Mutex<Vec<u64>>);
+
+        #[async_trait]
+        impl crate::LanguageServer for Backend {
+            async fn initialize(&self, _: lsp::InitializeParams) -> crate::jsonrpc::Result<lsp::InitializeResult> {
+                Ok(lsp::InitializeResult::default())
+            }
+
+            async fn shutdown(&self) -> crate::jsonrpc::Result<()> {
+                Ok(())
+            }
+        }
+
+        fn service() -> Spawn<LspService> {
+            let (service, _) = LspService::build(|_| Backend::default())
+                .custom_method::<CustomRequest, _, _>(|_, params| async move { Ok(params.value * 2) })
+                .custom_notification::<CustomNotification, _, _>(|server, params| async move {
+                    server.0.lock().unwrap().push(params.value);
+                })
+                .finish();
+            Spawn::new(service)
+        }
+
+        async fn initialize(service: &mut Spawn<LspService>) {
+            let initialize: Incoming = serde_json::from_str(INITIALIZE_REQUEST).unwrap();
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert!(matches!(service.call(initialize).await, Ok(Some(_))));
+        }
+
+        #[tokio::test]
+        async fn request() {
+            let mut service = service();
+            initialize(&mut service).await;
+
+            let raw = json!({ "jsonrpc": "2.0", "method": "custom/request", "params": { "value": 21 }, "id": 2 });
+            let request: Incoming = serde_json::from_value(raw).unwrap();
+            let response = Response::ok(Id::Number(2), json!(42));
+            assert_eq!(service.poll_ready(), Poll::Ready(Ok(())));
+            assert_eq!(service.call(request).await, Ok(Some(Outgoing::Response(response))));
+        }
+
+        #[tokio::test]
+        async fn request_invalid_params() {
+            let mut service = service();
+            initialize(&mut service).await;
+
+            let raw = json!({ "jsonrpc": "2.0", "method": "custom/request", "params": { "value": "foo" }, "id": 2 });
+            let request: Incoming = serde_json::from_value(raw).unwrap();
+            let response = match service.call(request).await {
+                Ok(Some(Outgoing::Response(response))) => response,
+                other => panic!("unexpected response: {:?}", other),
+            };
+            let (id, result) = response.into_parts();
+            assert_eq!(id, Some(Id::Number(2)));
+            assert_eq!(result.unwrap_err().code, crate::jsonrpc::ErrorCode::InvalidParams);
+        }
+
+        #[tokio::test]
+        async fn request_uninitialized() {
+            let mut service = service();
+
+            let raw = json!({ "jsonrpc": "2.0", "method": "custom/request", "params": { "value": 21 }, "id": 2 });
+            let request: Incoming = serde_json::from_value(raw).unwrap();
+            let response = Response::error(Some(Id::Number(2)), crate::jsonrpc::not_initialized_error());
+            assert_eq!(service.call(request).await, Ok(Some(Outgoing::Response(response))));
+        }
+
+        #[tokio::test]
+        async fn request_cancel() {
+            let (service, _) = LspService::build(|_| Backend::default())
+                .custom_method::<CustomRequest, _, _>(|_, _| async move {
+                    tokio::time::sleep(Duration::from_secs(50)).await;
+                    Ok(0)
+                })
+                .finish();
+            let mut service = Spawn::new(service);
+            initialize(&mut service).await;
+
+            let raw = json!({ "jsonrpc": "2.0", "method": "custom/request", "params": { "value": 21 }, "id": 2 });
+            let request: Incoming = serde_json::from_value(raw).unwrap();
+            let pending = tokio::spawn(service.call(request));
+
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let raw = json!({ "jsonrpc": "2.0", "method": "$/cancelRequest", "params": { "id": 2 } });
+            let cancel: Incoming = serde_json::from_value(raw).unwrap();
+            assert_eq!(service.call(cancel).await, Ok(None));
+
+            let response = Response::error(Some(Id::Number(2)), Error::request_cancelled());
+            assert_eq!(pending.await.unwrap(), Ok(Some(Outgoing::Response(response))));
+        }
+
+        #[tokio::test]
+        async fn notification() {
+            let server = Arc::new(Backend::default());
+            let (service, _) = LspService::build(|_| server.clone())
+                .custom_notification::<CustomNotification, _, _>(|server, params| async move {
+                    server.0.lock().unwrap().push(params.value);
+                })
+                .finish();
+            let mut service = Spawn::new(service);
+            initialize(&mut service).await;
+
+            let raw = json!({ "jsonrpc": "2.0", "method": "custom/notification", "params": { "value": 1 } });
+            let notification: Incoming = serde_json::from_value(raw).unwrap();
+            assert_eq!(service.call(notification).await, Ok(None));
+
+            let raw = json!({ "jsonrpc": "2.0", "method": "custom/notification", "params": { "value": "foo" } });
+            let notification: Incoming = serde_json::from_value(raw).unwrap();
+            assert_eq!(service.call(notification).await, Ok(None));
+
+            assert_eq!(*server.0.lock().unwrap(), vec![1]);
+        }
+
+        #[test]
+        #[should_panic]
+        fn register_twice() {
+            let _ = LspService::build(|_| Backend::default())
+                .custom_method::<CustomRequest, _, _>(|_, _| async move { Ok(0) })
+                .custom_method::<CustomRequest, _, _>(|_, _| async move { Ok(0) });
+        }
+
+        #[test]
+        fn debug() {
+            let _ = format!("{:?}", service());
+        }
+    }
+
     mod exited_error {
         use super::*;
 
@@ -264,7 +1080,7 @@ mod tests {
 
         #[tokio::test]
         async fn is_terminated() {
-            let (_, mut messages) = LspService::new(|_| Mock::default());
+            let (_, mut messages) = LspService::new(|_| Mock);
             assert!(!messages.is_terminated());
             while messages.next().await.is_some() {}
             assert!(messages.is_terminated());
@@ -272,7 +1088,7 @@ mod tests {
 
         #[tokio::test]
         async fn poll_next() {
-            let (_, mut messages) = LspService::new(|_| Mock::default());
+            let (_, mut messages) = LspService::new(|_| Mock);
             messages.next().await;
         }
     }
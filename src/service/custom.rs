@@ -0,0 +1,143 @@
+//! Registry of custom JSON-RPC methods handled outside of the `LanguageServer` trait.
+
+use crate::{
+    jsonrpc::{not_initialized_error, Error, Id, Outgoing, Params, Response, ServerRequests},
+    server::{State, StateKind},
+    service::ExitedError,
+};
+use futures::{future, FutureExt};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Formatter},
+    future::Future,
+    pin::Pin,
+};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+type RequestHandler = Box<dyn Fn(Option<Value>) -> Params<BoxFuture<crate::jsonrpc::Result<Value>>> + Send + Sync>;
+
+type NotificationHandler = Box<dyn Fn(Option<Value>) -> Params<BoxFuture<()>> + Send + Sync>;
+
+enum Method {
+    Request(RequestHandler),
+    Notification(NotificationHandler),
+}
+
+/// A hashmap containing typed handlers for custom requests and notifications, keyed by method name.
+#[derive(Default)]
+pub(crate) struct CustomMethods(HashMap<&'static str, Method>);
+
+impl CustomMethods {
+    /// Registers a handler for the custom request `R`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a handler has already been registered for the same method name.
+    pub(crate) fn insert_request<R, F, Fut>(&mut self, handler: F)
+    where
+        R: lsp::request::Request,
+        F: Fn(R::Params) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = crate::jsonrpc::Result<R::Result>> + Send + 'static,
+    {
+        let handler: RequestHandler = Box::new(move |params| match parse::<R::Params>(params) {
+            Params::Valid(params) => {
+                let fut = handler(params).map(|result| result.map(|v| serde_json::to_value(v).unwrap()));
+                Params::Valid(fut.boxed())
+            },
+            Params::Invalid(e) => Params::Invalid(e),
+        });
+        self.insert(R::METHOD, Method::Request(handler));
+    }
+
+    /// Registers a handler for the custom notification `N`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a handler has already been registered for the same method name.
+    pub(crate) fn insert_notification<N, F, Fut>(&mut self, handler: F)
+    where
+        N: lsp::notification::Notification,
+        F: Fn(N::Params) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler: NotificationHandler = Box::new(move |params| match parse::<N::Params>(params) {
+            Params::Valid(params) => Params::Valid(handler(params).boxed()),
+            Params::Invalid(e) => Params::Invalid(e),
+        });
+        self.insert(N::METHOD, Method::Notification(handler));
+    }
+
+    fn insert(&mut self, name: &'static str, method: Method) {
+        if self.0.insert(name, method).is_some() {
+            panic!("custom method {:?} registered more than once", name);
+        }
+    }
+
+    /// Returns `true` if a handler is registered for the given method name.
+    pub(crate) fn contains(&self, method: &str) -> bool {
+        self.0.contains_key(method)
+    }
+
+    /// Routes an incoming message to the handler registered for its method name.
+    ///
+    /// Requests are executed through `pending` so that they can be cancelled like any other
+    /// request. Invalid parameters are answered with an "invalid params" error.
+    pub(crate) fn handle(
+        &self,
+        state: &State,
+        pending: &ServerRequests,
+        method: &str,
+        id: Option<Id>,
+        params: Option<Value>,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Outgoing>, ExitedError>> + Send>> {
+        match (self.0.get(method), id, state.get()) {
+            (Some(Method::Request(handler)), Some(id), StateKind::Initialized) => match handler(params) {
+                Params::Valid(fut) => pending
+                    .execute(id, method, fut)
+                    .map(|v| Ok(Some(Outgoing::Response(v))))
+                    .boxed(),
+                Params::Invalid(e) => {
+                    log::error!("invalid parameters for {:?} request", method);
+                    let res = Response::error(Some(id), Error::invalid_params(e));
+                    future::ok(Some(Outgoing::Response(res))).boxed()
+                },
+            },
+            (Some(Method::Notification(handler)), None, StateKind::Initialized) => match handler(params) {
+                Params::Valid(fut) => fut.map(|()| Ok(None)).boxed(),
+                Params::Invalid(_) => {
+                    log::warn!("invalid parameters for {:?} notification", method);
+                    future::ok(None).boxed()
+                },
+            },
+            (_, Some(id), StateKind::Uninitialized) => {
+                let res = Response::error(Some(id), not_initialized_error());
+                future::ok(Some(Outgoing::Response(res))).boxed()
+            },
+            (_, Some(id), _) => {
+                let res = Response::error(Some(id), Error::invalid_request());
+                future::ok(Some(Outgoing::Response(res))).boxed()
+            },
+            (_, None, _) => future::ok(None).boxed(),
+        }
+    }
+}
+
+impl Debug for CustomMethods {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+fn parse<T: DeserializeOwned>(params: Option<Value>) -> Params<T> {
+    match params {
+        // Methods whose parameters are `()` or optional may omit the `params` field entirely.
+        None => match serde_json::from_value(Value::Null) {
+            Ok(v) => Params::Valid(v),
+            Err(_) => Params::Invalid("Missing params field".to_string()),
+        },
+        Some(v) => serde_json::from_value(v).unwrap_or_else(|e| Params::Invalid(e.to_string())),
+    }
+}
@@ -0,0 +1,15 @@
+//! Timers which work with whichever async runtime is enabled.
+
+use std::time::Duration;
+
+/// Waits until `duration` has elapsed.
+#[cfg(feature = "runtime-tokio")]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await;
+}
+
+/// Waits until `duration` has elapsed.
+#[cfg(feature = "runtime-agnostic")]
+pub(crate) async fn sleep(duration: Duration) {
+    futures_timer::Delay::new(duration).await;
+}
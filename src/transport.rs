@@ -11,28 +11,96 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use super::{
-    codec::LanguageServerCodec,
-    jsonrpc::{self, Incoming, Outgoing, Response},
+    codec::{Framing, LanguageServerCodec},
+    jsonrpc::{self, ErrorCode, Id, Incoming, Outgoing, Response},
+    recording::{Direction, Recorder},
+    ExitStatus,
+    ExitedError,
 };
 use futures::{
     channel::mpsc,
     future::{self, Either, FutureExt, TryFutureExt},
     sink::SinkExt,
     stream::{self, Empty, Stream, StreamExt},
+    task::AtomicWaker,
 };
 use std::{
+    collections::VecDeque,
     error::Error,
+    future::Future,
+    io,
     pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
 };
+use thiserror::Error;
 use tower_service::Service;
 
+/// Text synchronization notifications, which [`Server::ordered_text_sync`] processes in order.
+const TEXT_SYNC_METHODS: &[&str] = &[
+    "textDocument/didOpen",
+    "textDocument/didChange",
+    "textDocument/willSave",
+    "textDocument/didSave",
+    "textDocument/didClose",
+];
+
+/// Maximum number of messages read ahead while a text synchronization notification is handled
+/// with [`Server::ordered_text_sync`]. Reading stops once this many messages are waiting, unless
+/// the client still owes responses to the server.
+const MAX_TEXT_SYNC_BACKLOG: usize = 128;
+
+/// Maximum number of server messages written while a ready response is held back, so that a
+/// server which keeps sending messages cannot delay the response indefinitely.
+const MAX_MESSAGES_BEFORE_RESPONSE: usize = 64;
+
+/// Error which stops [`Server::serve`] before the language server has exited.
+#[derive(Debug, Error)]
+pub enum ServeError {
+    /// Failed to read from `stdin`, e.g. because the client disconnected.
+    #[error("failed to read message: {0}")]
+    Read(io::Error),
+    /// Failed to write to `stdout`, e.g. because the client disconnected.
+    #[error("failed to write message: {0}")]
+    Write(io::Error),
+    /// Failed to encode an outgoing message.
+    #[error("failed to encode message: {0}")]
+    Codec(Box<dyn Error + Send + Sync>),
+}
+
+/// Returns the I/O error which caused reading or writing a message to fail, or the codec error.
+#[cfg(feature = "runtime-tokio")]
+pub(crate) fn into_io_error(error: crate::codec::ParseError) -> Result<io::Error, crate::codec::ParseError> {
+    match error {
+        crate::codec::ParseError::Encode(error) => Ok(error),
+        error => Err(error),
+    }
+}
+
+/// Returns the I/O error which caused reading or writing a message to fail, or the codec error.
+#[cfg(feature = "runtime-agnostic")]
+pub(crate) fn into_io_error<E: Error + Send + Sync + 'static>(error: E) -> Result<io::Error, E> {
+    let source = error.source().and_then(|source| source.downcast_ref::<io::Error>());
+    match source.map(io::Error::kind) {
+        Some(kind) => Ok(io::Error::new(kind, error)),
+        None => Err(error),
+    }
+}
+
 /// Server for processing requests and responses on standard I/O or TCP.
 #[derive(Debug)]
 pub struct Server<I, O, S = Nothing> {
     stdin: I,
     stdout: O,
     interleave: S,
+    framing: Framing,
+    max_message_size: Option<usize>,
+    concurrency: usize,
+    ordered_text_sync: bool,
+    recorder: Option<Recorder>,
 }
 
 impl<I, O> Server<I, O, Nothing>
@@ -46,6 +114,11 @@ where
             stdin,
             stdout,
             interleave: Nothing::new(),
+            framing: Framing::ContentLength,
+            max_message_size: None,
+            concurrency: 4,
+            ordered_text_sync: false,
+            recorder: None,
         }
     }
 }
@@ -65,11 +138,96 @@ where
             stdin: self.stdin,
             stdout: self.stdout,
             interleave: stream,
+            framing: self.framing,
+            max_message_size: self.max_message_size,
+            concurrency: self.concurrency,
+            ordered_text_sync: self.ordered_text_sync,
+            recorder: self.recorder,
         }
     }
 
+    /// Sets how messages are delimited on both `stdin` and `stdout`.
+    ///
+    /// The default is [`Framing::ContentLength`], as required by the specification.
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Rejects incoming messages whose body is larger than `limit` bytes. Unlimited by default.
+    ///
+    /// Oversized messages are skipped without being buffered and answered with a parse error, so
+    /// a misbehaving client cannot make the server allocate arbitrary amounts of memory.
+    pub fn max_message_size(mut self, limit: usize) -> Self {
+        self.max_message_size = Some(limit);
+        self
+    }
+
+    /// Sets the maximum number of messages processed concurrently. The default is 4.
+    ///
+    /// Responses are always written in the order of their requests, so a slow request holds back
+    /// the responses to later requests until it completes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        assert!(limit > 0, "concurrency limit must be at least 1");
+        self.concurrency = limit;
+        self
+    }
+
+    /// Processes text synchronization notifications strictly in order.
+    ///
+    /// When enabled, a `textDocument/didOpen`, `didChange`, `willSave`, `didSave` or `didClose`
+    /// notification is handled to completion before any later message starts processing, so
+    /// handlers never observe stale document state. Responses to server-to-client requests are
+    /// still dispatched in the meantime, since the notification handler may be waiting on them,
+    /// and so are `$/cancelRequest` notifications, unless the request they cancel is itself
+    /// waiting for the notification.
+    ///
+    /// Up to 128 other messages are read ahead while a notification is being handled. Reading
+    /// then stops until the handler completes, so a slow handler holds back the client instead of
+    /// making the server buffer messages without limit. Reading goes on while the client has not
+    /// answered every request sent to it, though, so that the response a handler waits for is not
+    /// stuck behind the messages read ahead.
+    ///
+    /// Disabled by default, in which case notifications run concurrently with other messages.
+    pub fn ordered_text_sync(mut self, enabled: bool) -> Self {
+        self.ordered_text_sync = enabled;
+        self
+    }
+
+    /// Records the session to `writer`, e.g. a file, to reproduce it later with [`Replay`].
+    ///
+    /// Every message which was read from `stdin` or written to `stdout` is written as a
+    /// [`Record`] in JSON, one per line, with its direction and a timestamp. Messages which could
+    /// not be decoded are not recorded, and neither are the parse errors sent in response to them.
+    /// Failing to write the recording is logged, but does not stop the server.
+    ///
+    /// [`Replay`]: crate::recording::Replay
+    /// [`Record`]: crate::recording::Record
+    pub fn record<W>(mut self, writer: W) -> Self
+    where
+        W: AsyncWrite + Send + 'static,
+    {
+        self.recorder = Some(Recorder::new(writer));
+        self
+    }
+
     /// Spawns the service with messages read through `stdin` and responses written to `stdout`.
-    pub async fn serve<T>(self, mut service: T)
+    ///
+    /// Resolves once the service has exited or `stdin` is closed, after all pending responses and
+    /// the messages already queued in the interleaved stream have been written to `stdout`. The
+    /// interleaved stream is dropped afterwards, so later messages sent through a [`Client`] fail.
+    ///
+    /// The returned [`ExitStatus`] reports whether the client sent `shutdown` before `exit`. If
+    /// reading or writing fails instead, e.g. because the client disconnected, serving stops
+    /// immediately with a [`ServeError`]. Malformed incoming messages are not fatal; they are
+    /// answered with a parse error as required by JSON-RPC.
+    ///
+    /// [`Client`]: crate::Client
+    pub async fn serve<T>(self, mut service: T) -> Result<ExitStatus, ServeError>
     where
         T: Service<Incoming, Response = Option<Outgoing>> + Send + 'static,
         T::Error: Into<Box<dyn Error + Send + Sync>>,
@@ -77,44 +235,327 @@ where
     {
         let (mut sender, receiver) = mpsc::channel(16);
 
-        let mut framed_stdin = FramedRead::new(self.stdin, LanguageServerCodec::default());
-        let framed_stdout = FramedWrite::new(self.stdout, LanguageServerCodec::default());
-        let responses = receiver.buffered(4).filter_map(future::ready);
-        let interleave = self.interleave.fuse();
+        let mut codec = LanguageServerCodec::new(self.framing);
+        if let Some(limit) = self.max_message_size {
+            codec = codec.max_message_size(limit);
+        }
+        let framed_stdin = FramedRead::new(self.stdin, codec);
+        let framed_stdout = FramedWrite::new(self.stdout, LanguageServerCodec::new(self.framing));
+        let mut responses = Box::pin(receiver.buffered(self.concurrency).filter_map(future::ready));
+        let mut interleave = Box::pin(self.interleave.fuse());
+        let ordered_text_sync = self.ordered_text_sync;
+
+        let (record, recorder) = match self.recorder {
+            Some(recorder) => {
+                let (record, recorder) = recorder.start();
+                (Some(record), Either::Left(recorder))
+            },
+            None => (None, Either::Right(future::ready(()))),
+        };
+        let record_outgoing = record.clone();
+        let unanswered = Arc::new(Unanswered::default());
+        let unanswered_outgoing = unanswered.clone();
+
+        // Messages from the server and responses are written in turn, so that a server which keeps
+        // sending notifications cannot starve responses. A ready response is held back until the
+        // messages already queued have been written, so that any notifications sent by its request
+        // handler (e.g. partial results) precede it. Once the reader has stopped and all responses
+        // are written, only the messages which are already queued are written, since the
+        // interleaved stream may never end on its own.
+        let mut held = None;
+        let mut written_before_held = 0;
+        let mut interleave_turn = false;
+        let mut responses_done = false;
+        let outgoing = stream::poll_fn(move |cx| loop {
+            if let Some(response) = held.take() {
+                if written_before_held < MAX_MESSAGES_BEFORE_RESPONSE {
+                    if let Poll::Ready(Some(msg)) = interleave.as_mut().poll_next(cx) {
+                        written_before_held += 1;
+                        held = Some(response);
+                        return Poll::Ready(Some(msg));
+                    }
+                }
+                written_before_held = 0;
+                return Poll::Ready(Some(response));
+            }
 
-        let printer = stream::select(responses, interleave)
+            interleave_turn = !interleave_turn;
+            if interleave_turn {
+                if let Poll::Ready(Some(msg)) = interleave.as_mut().poll_next(cx) {
+                    return Poll::Ready(Some(msg));
+                }
+            }
+            if !responses_done {
+                match responses.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(response)) => {
+                        held = Some(response);
+                        continue;
+                    },
+                    Poll::Ready(None) => responses_done = true,
+                    Poll::Pending => {},
+                }
+            }
+            return match interleave.as_mut().poll_next(cx) {
+                Poll::Ready(Some(msg)) => Poll::Ready(Some(msg)),
+                _ if responses_done => Poll::Ready(None),
+                _ => Poll::Pending,
+            };
+        });
+
+        let printer = outgoing
+            .inspect(move |msg| {
+                if let Outgoing::Request(req) = msg {
+                    if req.id().is_some() {
+                        unanswered_outgoing.sent();
+                    }
+                }
+                match record_outgoing {
+                    Some(ref record) if !answers_undecodable(msg) => record.send(Direction::Outgoing, msg),
+                    _ => {},
+                }
+            })
             .map(Ok)
-            .forward(framed_stdout.sink_map_err(|e| log::error!("failed to encode message: {}", e)))
-            .map(|_| ());
+            .forward(framed_stdout.sink_map_err(|err| match into_io_error(err) {
+                Ok(err) => ServeError::Write(err),
+                Err(err) => ServeError::Codec(err.into()),
+            }));
 
         let reader = async move {
-            while let Some(msg) = framed_stdin.next().await {
-                let request = match msg {
+            let mut messages = framed_stdin;
+
+            // The stream of messages may end after a decode error, but the codec has skipped the
+            // offending message, so reading resumes once before treating the end as final.
+            let mut decode_failed = false;
+
+            // Text synchronization notification which must complete before later messages start,
+            // and the messages received in the meantime.
+            let mut barrier = None;
+            let mut backlog = VecDeque::new();
+
+            // The specification requires exiting with code 0 only if `shutdown` preceded `exit`.
+            let mut shutdown_received = false;
+
+            loop {
+                if let Err(err) = future::poll_fn(|cx| service.poll_ready(cx)).await {
+                    return Ok(exit_status(err.into(), shutdown_received));
+                }
+
+                let msg = if let Some(notification) = barrier.take() {
+                    // Once the backlog is full, keep reading only while the notification handler
+                    // may be waiting for a response from the client.
+                    let full = backlog.len() >= MAX_TEXT_SYNC_BACKLOG;
+                    let next = async {
+                        if full {
+                            unanswered.wait().await;
+                        }
+                        next_message(&mut messages, &mut service).await
+                    };
+                    futures::pin_mut!(next);
+                    match future::select(notification, next).await {
+                        Either::Left((response, _)) => {
+                            if sender.send(Either::Right(future::ready(response))).await.is_err() {
+                                return Ok(ExitStatus::Failure);
+                            }
+                            continue;
+                        },
+                        Either::Right((Ok(Some(Ok(msg))), notification)) if bypasses_text_sync(&msg) => {
+                            barrier = Some(notification);
+                            // A cancellation must not overtake the request it cancels.
+                            let cancelled = cancelled_id(&msg);
+                            let is_cancelled = |queued: &Result<Incoming, _>| match (queued, &cancelled) {
+                                (Ok(Incoming::Request(req)), Some(id)) => req.id() == Some(id),
+                                _ => false,
+                            };
+                            if backlog.iter().any(is_cancelled) {
+                                backlog.push_back(Ok(msg));
+                                continue;
+                            }
+                            Ok(msg)
+                        },
+                        Either::Right((Ok(Some(msg)), notification)) => {
+                            decode_failed = msg.is_err();
+                            barrier = Some(notification);
+                            backlog.push_back(msg);
+                            continue;
+                        },
+                        Either::Right((Ok(None), notification)) if decode_failed => {
+                            decode_failed = false;
+                            barrier = Some(notification);
+                            continue;
+                        },
+                        Either::Right((Ok(None), notification)) => {
+                            let response = notification.await;
+                            if sender.send(Either::Right(future::ready(response))).await.is_err() {
+                                return Ok(ExitStatus::Failure);
+                            }
+                            continue;
+                        },
+                        Either::Right((Err(err), _)) => return Ok(exit_status(err.into(), shutdown_received)),
+                    }
+                } else if let Some(msg) = backlog.pop_front() {
+                    msg
+                } else {
+                    match next_message(&mut messages, &mut service).await {
+                        Ok(Some(msg)) => {
+                            decode_failed = msg.is_err();
+                            msg
+                        },
+                        Ok(None) if decode_failed => {
+                            decode_failed = false;
+                            continue;
+                        },
+                        Ok(None) => break,
+                        Err(err) => return Ok(exit_status(err.into(), shutdown_received)),
+                    }
+                };
+
+                let request = match msg.map_err(into_io_error) {
                     Ok(req) => req,
-                    Err(err) => {
+                    Err(Ok(err)) => return Err(ServeError::Read(err)),
+                    Err(Err(err)) => {
                         log::error!("failed to decode message: {}", err);
                         let response = Response::error(None, jsonrpc::Error::parse_error());
                         let response_fut = future::ready(Some(Outgoing::Response(response)));
-                        sender.send(Either::Right(response_fut)).await.unwrap();
+                        if sender.send(Either::Right(response_fut)).await.is_err() {
+                            return Ok(ExitStatus::Failure);
+                        }
                         continue;
                     },
                 };
 
-                if let Err(err) = future::poll_fn(|cx| service.poll_ready(cx)).await {
-                    log::error!("{}", display_sources(err.into().as_ref()));
-                    return;
+                if let Some(ref record) = record {
+                    record.send(Direction::Incoming, &request);
                 }
 
+                let is_text_sync = match request {
+                    Incoming::Request(ref req) => {
+                        shutdown_received |= req.method() == "shutdown" && req.id().is_some();
+                        TEXT_SYNC_METHODS.contains(&req.method())
+                    },
+                    Incoming::Response(_) => {
+                        unanswered.answered();
+                        false
+                    },
+                };
+
                 let response_fut = service.call(request).unwrap_or_else(|err| {
                     log::error!("{}", display_sources(err.into().as_ref()));
                     None
                 });
 
-                sender.send(Either::Left(response_fut)).await.unwrap();
+                if ordered_text_sync && is_text_sync {
+                    barrier = Some(response_fut.boxed());
+                } else if sender.send(Either::Left(response_fut)).await.is_err() {
+                    // The printer has stopped, its error is returned below.
+                    return Ok(ExitStatus::Failure);
+                }
+            }
+
+            Ok(ExitStatus::Failure)
+        };
+
+        // Stop reading as soon as writing fails, but let pending output be written otherwise.
+        let served = async move {
+            futures::pin_mut!(reader, printer);
+            match future::select(reader, printer).await {
+                Either::Left((status, printer)) => printer.await.and(status),
+                Either::Right((written, reader)) => written.and(reader.await),
             }
         };
 
-        futures::join!(reader, printer);
+        // The recording is complete once both the reader and the printer have been dropped.
+        let (status, ()) = future::join(served, recorder).await;
+        status
+    }
+}
+
+/// Returns `true` if `msg` is the parse error sent in response to a message which could not be
+/// decoded. The service never sends one, since it only receives decoded messages.
+fn answers_undecodable(msg: &Outgoing) -> bool {
+    match msg {
+        Outgoing::Response(res) => res.id().is_none() && res.error_code() == Some(ErrorCode::ParseError),
+        Outgoing::Request(_) => false,
+    }
+}
+
+/// Returns `true` if `msg` is dispatched while an ordered text synchronization notification is
+/// still being handled, instead of waiting for it.
+fn bypasses_text_sync(msg: &Incoming) -> bool {
+    match msg {
+        // The notification handler may be waiting for this response.
+        Incoming::Response(_) => true,
+        Incoming::Request(req) => req.method() == "$/cancelRequest",
+    }
+}
+
+/// Returns the ID of the request cancelled by `msg`, if it is a `$/cancelRequest` notification.
+fn cancelled_id(msg: &Incoming) -> Option<Id> {
+    match msg.method() {
+        Some("$/cancelRequest") => serde_json::from_value(msg.params()?.get("id")?.clone()).ok(),
+        _ => None,
+    }
+}
+
+/// Number of requests written to the client which it has not answered yet.
+#[derive(Debug, Default)]
+struct Unanswered {
+    count: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl Unanswered {
+    fn sent(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.waker.wake();
+    }
+
+    fn answered(&self) {
+        // Responses to unknown requests are ignored by the service as well.
+        let _ = self.count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    }
+
+    /// Resolves once the client owes the server at least one response.
+    fn wait(&self) -> impl Future<Output = ()> + '_ {
+        future::poll_fn(move |cx| {
+            self.waker.register(cx.waker());
+            if self.count.load(Ordering::SeqCst) > 0 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+    }
+}
+
+/// Waits for the next message, unless `service` stops being ready first, e.g. because the server
+/// exited while the client was silent.
+fn next_message<'a, S, T>(
+    messages: &'a mut S,
+    service: &'a mut T,
+) -> impl Future<Output = Result<Option<S::Item>, T::Error>> + Unpin + 'a
+where
+    S: Stream + Unpin,
+    T: Service<Incoming>,
+{
+    let failed = future::poll_fn(move |cx| match service.poll_ready(cx) {
+        Poll::Ready(Err(err)) => Poll::Ready(err),
+        _ => Poll::Pending,
+    });
+    future::select(messages.next(), failed).map(|next| match next {
+        Either::Left((msg, _)) => Ok(msg),
+        Either::Right((err, _)) => Err(err),
+    })
+}
+
+/// Returns the status to exit with once the service is no longer ready because of `error`.
+fn exit_status(error: Box<dyn Error + Send + Sync>, shutdown_received: bool) -> ExitStatus {
+    match error.downcast_ref::<ExitedError>() {
+        Some(_) if shutdown_received => ExitStatus::Success,
+        Some(_) => ExitStatus::Failure,
+        None => {
+            log::error!("{}", display_sources(error.as_ref()));
+            ExitStatus::Failure
+        },
     }
 }
 
@@ -148,7 +589,16 @@ impl Stream for Nothing {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::{future, future::Ready, stream};
+    use futures::{
+        channel::oneshot,
+        future::{self, BoxFuture, Ready},
+        stream,
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    };
 
     #[cfg(feature = "runtime-agnostic")]
     use futures::io::Cursor;
@@ -194,7 +644,7 @@ mod tests {
         let message = format!("Content-Length: {}\r\n\r\n{}", invalid.len(), invalid).into_bytes();
         let (mut stdin, mut stdout) = (Cursor::new(message), Vec::new());
 
-        Server::new(&mut stdin, &mut stdout).serve(MockService).await;
+        Server::new(&mut stdin, &mut stdout).serve(MockService).await.unwrap();
 
         assert_eq!(stdin.position(), 48);
         let err = r#"{"jsonrpc":"2.0","error":{"code":-32700,"message":"Parse error"},"id":null}"#;
@@ -202,6 +652,26 @@ mod tests {
         assert_eq!(stdout, output);
     }
 
+    #[tokio::test]
+    async fn skips_oversized_messages() {
+        let params = "data".repeat(100);
+        let oversized = format!(r#"{{"jsonrpc":"2.0","method":"foo","params":"{}"}}"#, params);
+        let mut message = format!("Content-Length: {}\r\n\r\n{}", oversized.len(), oversized).into_bytes();
+        message.extend(mock_request());
+        let (mut stdin, mut stdout) = (Cursor::new(message), Vec::new());
+
+        Server::new(&mut stdin, &mut stdout)
+            .max_message_size(REQUEST.len())
+            .serve(MockService)
+            .await
+            .unwrap();
+
+        let err = r#"{"jsonrpc":"2.0","error":{"code":-32700,"message":"Parse error"},"id":null}"#;
+        let mut output = format!("Content-Length: {}\r\n\r\n{}", err.len(), err).into_bytes();
+        output.extend(mock_response());
+        assert_eq!(stdout, output);
+    }
+
     #[tokio::test]
     async fn interleaves_messages() {
         let message = Outgoing::Response(serde_json::from_str(RESPONSE).unwrap());
@@ -211,22 +681,436 @@ mod tests {
         Server::new(&mut stdin, &mut stdout)
             .interleave(messages)
             .serve(MockService)
-            .await;
+            .await
+            .unwrap();
 
         assert_eq!(stdin.position(), 80);
         let output: Vec<_> = mock_response().into_iter().chain(mock_response()).collect();
         assert_eq!(stdout, output);
     }
 
+    #[tokio::test]
+    async fn interleaved_messages_precede_responses() {
+        const NOTIFICATION: &str = r#"{"jsonrpc":"2.0","method":"$/progress","params":{"token":1,"value":[]}}"#;
+
+        let message = serde_json::from_str(NOTIFICATION).unwrap();
+        let messages = stream::iter(vec![Outgoing::Request(message)]);
+
+        let (mut stdin, mut stdout) = mock_stdio();
+        Server::new(&mut stdin, &mut stdout)
+            .interleave(messages)
+            .serve(MockService)
+            .await
+            .unwrap();
+
+        let notification = format!("Content-Length: {}\r\n\r\n{}", NOTIFICATION.len(), NOTIFICATION).into_bytes();
+        let output: Vec<_> = notification.into_iter().chain(mock_response()).collect();
+        assert_eq!(stdout, output);
+    }
+
+    #[tokio::test]
+    async fn busy_server_does_not_starve_responses() {
+        const NOTIFICATION: &str = r#"{"jsonrpc":"2.0","method":"$/progress","params":{"token":1,"value":[]}}"#;
+
+        let message = Outgoing::Request(serde_json::from_str(NOTIFICATION).unwrap());
+        let messages = stream::iter(vec![message; 1000]);
+
+        let (mut stdin, mut stdout) = mock_stdio();
+        Server::new(&mut stdin, &mut stdout)
+            .interleave(messages)
+            .serve(MockService)
+            .await
+            .unwrap();
+
+        let stdout = String::from_utf8(stdout).unwrap();
+        let bodies: Vec<_> = stdout.split("Content-Length").collect();
+        assert_eq!(bodies.len(), 1002);
+        let position = bodies.iter().position(|body| body.ends_with(RESPONSE)).unwrap();
+        assert!(position <= MAX_MESSAGES_BEFORE_RESPONSE + 2, "response written at {}", position);
+    }
+
     #[tokio::test]
     async fn serves_on_stdio() {
         let (mut stdin, mut stdout) = mock_stdio();
-        Server::new(&mut stdin, &mut stdout).serve(MockService).await;
+        Server::new(&mut stdin, &mut stdout).serve(MockService).await.unwrap();
 
         assert_eq!(stdin.position(), 80);
         assert_eq!(stdout, mock_response());
     }
 
+    #[tokio::test]
+    async fn serves_line_delimited() {
+        let mut stdin = Cursor::new(format!("{}\n", REQUEST).into_bytes());
+        let mut stdout = Vec::new();
+        Server::new(&mut stdin, &mut stdout)
+            .framing(Framing::LineDelimited)
+            .serve(MockService)
+            .await
+            .unwrap();
+
+        assert_eq!(stdout, format!("{}\n", RESPONSE).into_bytes());
+    }
+
+    fn mock_messages(messages: &[&str]) -> Cursor<Vec<u8>> {
+        let framed: String = messages
+            .iter()
+            .map(|msg| format!("Content-Length: {}\r\n\r\n{}", msg.len(), msg))
+            .collect();
+        Cursor::new(framed.into_bytes())
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct ConcurrencyService {
+        active: Arc<AtomicUsize>,
+        max_active: Arc<AtomicUsize>,
+    }
+
+    impl Service<Incoming> for ConcurrencyService {
+        type Error = String;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+        type Response = Option<Outgoing>;
+
+        fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Incoming) -> Self::Future {
+            let this = self.clone();
+            Box::pin(async move {
+                let active = this.active.fetch_add(1, Ordering::SeqCst) + 1;
+                this.max_active.fetch_max(active, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                this.active.fetch_sub(1, Ordering::SeqCst);
+                Ok(None)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_concurrency() {
+        for &(limit, expected) in [(1, 1), (2, 2), (4, 3)].iter() {
+            let mut stdin = mock_messages(&[REQUEST, REQUEST, REQUEST]);
+            let service = ConcurrencyService::default();
+            Server::new(&mut stdin, Vec::new())
+                .concurrency(limit)
+                .serve(service.clone())
+                .await
+                .unwrap();
+            assert_eq!(service.max_active.load(Ordering::SeqCst), expected);
+        }
+    }
+
+    /// Records the order in which messages start, or complete for `didChange` notifications,
+    /// which only complete once a response from the client arrives. If `client` is set, they send
+    /// a request to the client through it first.
+    #[derive(Clone, Debug, Default)]
+    struct SyncService {
+        events: Arc<Mutex<Vec<&'static str>>>,
+        response: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+        client: Option<mpsc::UnboundedSender<Outgoing>>,
+    }
+
+    impl Service<Incoming> for SyncService {
+        type Error = String;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+        type Response = Option<Outgoing>;
+
+        fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, message: Incoming) -> Self::Future {
+            let events = self.events.clone();
+            match message {
+                Incoming::Request(req) if req.method() == "textDocument/didChange" => {
+                    let (tx, rx) = oneshot::channel();
+                    *self.response.lock().unwrap() = Some(tx);
+                    if let Some(ref client) = self.client {
+                        let request = jsonrpc::ClientRequest::request::<lsp::request::WorkspaceConfiguration>(
+                            0,
+                            lsp::ConfigurationParams { items: Vec::new() },
+                        );
+                        client.unbounded_send(Outgoing::Request(request)).unwrap();
+                    }
+                    Box::pin(async move {
+                        rx.await.unwrap();
+                        events.lock().unwrap().push("didChange");
+                        Ok(None)
+                    })
+                },
+                Incoming::Request(req) if req.method() == "$/cancelRequest" => {
+                    events.lock().unwrap().push("cancel");
+                    Box::pin(future::ok(None))
+                },
+                Incoming::Request(_) => {
+                    events.lock().unwrap().push("request");
+                    Box::pin(future::ok(None))
+                },
+                Incoming::Response(_) => {
+                    events.lock().unwrap().push("response");
+                    self.response.lock().unwrap().take().unwrap().send(()).unwrap();
+                    Box::pin(future::ok(None))
+                },
+            }
+        }
+    }
+
+    const DID_CHANGE: &str = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{}}"#;
+
+    const CLIENT_RESPONSE: &str = r#"{"jsonrpc":"2.0","result":null,"id":0}"#;
+
+    #[tokio::test]
+    async fn orders_text_sync_notifications() {
+        // Only the second cancellation may overtake the notification, since the request cancelled
+        // by the first one is waiting for it.
+        const CANCEL_QUEUED: &str = r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":1}}"#;
+        const CANCEL_OTHER: &str = r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":2}}"#;
+
+        for &(ordered, expected) in [
+            (false, ["request", "cancel", "cancel", "response", "didChange"]),
+            (true, ["cancel", "response", "didChange", "request", "cancel"]),
+        ]
+        .iter()
+        {
+            let mut stdin = mock_messages(&[DID_CHANGE, REQUEST, CANCEL_QUEUED, CANCEL_OTHER, CLIENT_RESPONSE]);
+            let service = SyncService::default();
+            Server::new(&mut stdin, Vec::new())
+                .ordered_text_sync(ordered)
+                .serve(service.clone())
+                .await
+                .unwrap();
+            assert_eq!(*service.events.lock().unwrap(), expected);
+        }
+    }
+
+    /// Input which returns at most one message per read, counting the messages read entirely.
+    struct OneAtATime {
+        messages: VecDeque<Vec<u8>>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl OneAtATime {
+        fn read_into(&mut self, buf: &mut [u8]) -> usize {
+            let mut message = self.messages.pop_front().unwrap_or_default();
+            if message.len() > buf.len() {
+                self.messages.push_front(message.split_off(buf.len()));
+            } else if !message.is_empty() {
+                self.reads.fetch_add(1, Ordering::SeqCst);
+            }
+            buf[.. message.len()].copy_from_slice(&message);
+            message.len()
+        }
+    }
+
+    #[cfg(feature = "runtime-agnostic")]
+    impl AsyncRead for OneAtATime {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(self.read_into(buf)))
+        }
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    impl AsyncRead for OneAtATime {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context, buf: &mut tokio::io::ReadBuf) -> Poll<io::Result<()>> {
+            let read = self.read_into(buf.initialize_unfilled());
+            buf.advance(read);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn bounds_text_sync_backlog() {
+        let mut messages = vec![DID_CHANGE];
+        messages.extend(std::iter::repeat_n(REQUEST, MAX_TEXT_SYNC_BACKLOG + 10));
+        let messages = messages.iter().map(|msg| format!("Content-Length: {}\r\n\r\n{}", msg.len(), msg));
+        let reads = Arc::new(AtomicUsize::new(0));
+        let stdin = OneAtATime {
+            messages: messages.map(String::into_bytes).collect(),
+            reads: reads.clone(),
+        };
+
+        // The `didChange` notification never completes, since no response from the client arrives.
+        let service = SyncService::default();
+        let serve = Server::new(stdin, Vec::new()).ordered_text_sync(true).serve(service.clone());
+        let served = tokio::time::timeout(std::time::Duration::from_millis(100), serve).await;
+        assert!(served.is_err());
+        assert_eq!(reads.load(Ordering::SeqCst), 1 + MAX_TEXT_SYNC_BACKLOG);
+        assert!(service.events.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_client_responses_past_text_sync_backlog() {
+        let mut messages = vec![DID_CHANGE];
+        messages.extend(std::iter::repeat_n(REQUEST, MAX_TEXT_SYNC_BACKLOG + 10));
+        messages.push(CLIENT_RESPONSE);
+        let stdin = mock_messages(&messages);
+
+        // The `didChange` notification waits for the response to its request, which is only read
+        // once the backlog is full.
+        let (client, requests) = mpsc::unbounded();
+        let service = SyncService {
+            client: Some(client),
+            ..SyncService::default()
+        };
+        let serve = Server::new(stdin, Vec::new())
+            .interleave(requests)
+            .ordered_text_sync(true)
+            .serve(service.clone());
+        let served = tokio::time::timeout(std::time::Duration::from_secs(5), serve).await;
+        assert_eq!(served.unwrap().unwrap(), ExitStatus::Failure);
+
+        let events = service.events.lock().unwrap();
+        assert_eq!(events[.. 2], ["response", "didChange"]);
+        assert_eq!(events.len(), 2 + MAX_TEXT_SYNC_BACKLOG + 10);
+    }
+
+    /// Pipe whose other end has been closed.
+    #[derive(Debug)]
+    struct BrokenPipe;
+
+    #[cfg(feature = "runtime-agnostic")]
+    impl AsyncRead for BrokenPipe {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    impl AsyncRead for BrokenPipe {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut tokio::io::ReadBuf) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+    }
+
+    #[cfg(feature = "runtime-agnostic")]
+    impl AsyncWrite for BrokenPipe {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, _: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    impl AsyncWrite for BrokenPipe {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, _: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_io_errors() {
+        let result = Server::new(BrokenPipe, Vec::new()).serve(MockService).await;
+        assert!(matches!(result, Err(ServeError::Read(_))), "{:?}", result);
+
+        let (stdin, _) = mock_stdio();
+        let result = Server::new(stdin, BrokenPipe).serve(MockService).await;
+        assert!(matches!(result, Err(ServeError::Write(_))), "{:?}", result);
+    }
+
+    #[derive(Debug)]
+    struct Backend {
+        client: crate::Client,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::LanguageServer for Backend {
+        async fn initialize(&self, _: lsp::InitializeParams) -> jsonrpc::Result<lsp::InitializeResult> {
+            Ok(lsp::InitializeResult::default())
+        }
+
+        async fn shutdown(&self) -> jsonrpc::Result<()> {
+            self.client.log_message(lsp::MessageType::INFO, "shutting down").await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_with_exit_status() {
+        const INITIALIZE: &str = r#"{"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{}},"id":1}"#;
+        const SHUTDOWN: &str = r#"{"jsonrpc":"2.0","method":"shutdown","id":2}"#;
+        const EXIT: &str = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        const TRAILING: &str = r#"{"jsonrpc":"2.0","method":"shutdown","id":3}"#;
+
+        // The `shutdown` handler has not run yet when `exit` arrives, so its response is cancelled.
+        let cases = vec![
+            (vec![SHUTDOWN, EXIT, TRAILING], ExitStatus::Success, 1),
+            (vec![EXIT, TRAILING], ExitStatus::Failure, 0),
+            (vec![SHUTDOWN], ExitStatus::Failure, 2),
+        ];
+        for (messages, expected, written) in cases {
+            let (mut service, interleave) = crate::LspService::new(|client| Backend { client });
+            let initialize = serde_json::from_str(INITIALIZE).unwrap();
+            assert!(service.call(initialize).await.is_ok());
+
+            let mut stdin = mock_messages(&messages);
+            let mut stdout = Vec::new();
+            let status = Server::new(&mut stdin, &mut stdout)
+                .interleave(interleave)
+                .serve(service)
+                .await
+                .unwrap();
+            assert_eq!(status, expected);
+
+            let stdout = String::from_utf8(stdout).unwrap();
+            assert_eq!(stdout.matches("Content-Length").count(), written, "{}", stdout);
+        }
+    }
+
+    /// Input of a client which stays connected without ever sending a message.
+    struct Silent;
+
+    #[cfg(feature = "runtime-agnostic")]
+    impl AsyncRead for Silent {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    #[cfg(feature = "runtime-tokio")]
+    impl AsyncRead for Silent {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut tokio::io::ReadBuf) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn exits_when_parent_process_is_gone() {
+        let mut parent = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let (mut service, interleave) = crate::LspService::build(|client| Backend { client })
+            .watch_parent_process(std::time::Duration::from_millis(10))
+            .finish();
+        let params = serde_json::json!({ "processId": parent.id(), "capabilities": {} });
+        let initialize = serde_json::json!({ "jsonrpc": "2.0", "method": "initialize", "params": params, "id": 1 });
+        assert!(service.call(serde_json::from_value(initialize).unwrap()).await.is_ok());
+
+        // Serve on its own task, so that nothing but the exit itself wakes up the reader.
+        let serve = Server::new(Silent, Vec::new()).interleave(interleave).serve(service);
+        let serve = tokio::spawn(serve);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        parent.kill().unwrap();
+        parent.wait().unwrap();
+
+        let served = tokio::time::timeout(std::time::Duration::from_secs(5), serve).await;
+        let status = served.expect("serve did not return after the parent process exited");
+        assert_eq!(status.unwrap().unwrap(), ExitStatus::Failure);
+    }
+
     #[derive(Debug)]
     struct CustomError;
 
@@ -0,0 +1,106 @@
+//! Records logged by `LogLayer`, in their own test binary since they need a global logger.
+
+use futures::future;
+use lspower::{
+    jsonrpc::{Id, Incoming, Outgoing, Response},
+    layers::{Layer, LogLayer, LOG_TARGET},
+};
+use serde_json::json;
+use std::{
+    sync::Mutex,
+    task::{Context, Poll},
+};
+use tower_service::Service;
+
+/// Message and key-value pairs of a record.
+#[derive(Debug)]
+struct Captured {
+    message: String,
+    method: Option<String>,
+    id: Option<String>,
+    elapsed_ms: Option<f64>,
+}
+
+static RECORDS: Mutex<Vec<Captured>> = Mutex::new(Vec::new());
+
+/// Logger which keeps the records logged with [`LOG_TARGET`].
+struct Capture;
+
+impl log::Log for Capture {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == LOG_TARGET
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let kvs = record.key_values();
+        let get = |key: &str| kvs.get(log::kv::Key::from_str(key));
+        RECORDS.lock().unwrap().push(Captured {
+            message: record.args().to_string(),
+            method: get("method").map(|value| value.to_string()),
+            id: get("id").map(|value| value.to_string()),
+            elapsed_ms: get("elapsed_ms").and_then(|value| value.to_f64()),
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Service which fails the `custom` request and handles everything else without a response.
+#[derive(Debug)]
+struct FailsCustom;
+
+impl Service<Incoming> for FailsCustom {
+    type Error = String;
+    type Future = future::Ready<Result<Option<Outgoing>, String>>;
+    type Response = Option<Outgoing>;
+
+    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, msg: Incoming) -> Self::Future {
+        match msg.method() {
+            Some("custom") => future::err("custom failed".into()),
+            _ => future::ok(None),
+        }
+    }
+}
+
+#[test]
+fn logs_handlers() {
+    log::set_logger(&Capture).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+
+    let mut service = LogLayer.layer(FailsCustom);
+    futures::executor::block_on(async {
+        let initialized = Incoming::from_parts("initialized", None, Some(json!({})));
+        assert_eq!(service.call(initialized).await, Ok(None));
+        let custom = Incoming::from_parts("custom", Some(Id::Number(7)), None);
+        assert_eq!(service.call(custom).await, Err("custom failed".into()));
+        let response = Incoming::Response(Response::ok(Id::Number(0), json!(null)));
+        assert_eq!(service.call(response).await, Ok(None));
+    });
+
+    let records = RECORDS.lock().unwrap();
+    assert_eq!(records.len(), 4, "{:?}", records);
+
+    let fields: Vec<_> = records.iter().map(|record| (record.method.as_deref(), record.id.as_deref())).collect();
+    let expected = [
+        (Some("initialized"), None),
+        (Some("initialized"), None),
+        (Some("custom"), Some("7")),
+        (Some("custom"), Some("7")),
+    ];
+    assert_eq!(fields, expected);
+
+    assert_eq!(records[0].message, "initialized: started");
+    assert!(records[1].message.starts_with("initialized: finished in "), "{:?}", records[1]);
+    assert_eq!(records[2].message, "custom id=7: started");
+    assert!(records[3].message.starts_with("custom id=7: failed after "), "{:?}", records[3]);
+
+    let elapsed: Vec<_> = records.iter().map(|record| record.elapsed_ms.is_some()).collect();
+    assert_eq!(elapsed, [false, true, false, true]);
+}